@@ -1 +1,70 @@
-pub use unstyled_macro::{style, write_style};
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+
+pub use unstyled_macro::{global_style, style, style_file, write_style};
+
+///
+/// A scoped style, as returned by [`style!`]. It is used just like its scope class, which has to be
+/// added to the elements the style applies to.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Style {
+    class: &'static str,
+    css: &'static str,
+}
+
+impl Style {
+    #[doc(hidden)]
+    pub const fn new(class: &'static str, css: &'static str) -> Self {
+        Self { class, css }
+    }
+
+    /// The scope class of the style
+    pub const fn class(&self) -> &'static str {
+        self.class
+    }
+
+    /// The compiled (scoped) css of the style, as it is written to the merged stylesheet
+    pub const fn css(&self) -> &'static str {
+        self.css
+    }
+}
+
+impl Display for Style {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.class)
+    }
+}
+
+impl From<Style> for &'static str {
+    fn from(style: Style) -> Self {
+        style.class
+    }
+}
+
+impl From<Style> for String {
+    fn from(style: Style) -> Self {
+        style.class.to_string()
+    }
+}
+
+impl From<Style> for Cow<'static, str> {
+    fn from(style: Style) -> Self {
+        Cow::Borrowed(style.class)
+    }
+}
+
+///
+/// Allows `class=style` in `view!`. There is no `IntoClass` implementation, as leptos uses it for
+/// `class:name=condition`, which toggles the class written in the attribute name by a `bool`.
+///
+#[cfg(feature = "leptos")]
+impl leptos::IntoAttribute for Style {
+    fn into_attribute(self, _: leptos::Scope) -> leptos::Attribute {
+        leptos::Attribute::String(Cow::Borrowed(self.class))
+    }
+
+    fn into_attribute_boxed(self: Box<Self>, cx: leptos::Scope) -> leptos::Attribute {
+        self.into_attribute(cx)
+    }
+}
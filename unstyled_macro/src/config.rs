@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+
+use toml::Table;
+
+use crate::hash;
+
+/// Overrides the path the merged stylesheet is written to, relative to the working directory
+const OUTPUT_ENV: &str = "UNSTYLED_OUTPUT";
+
+///
+/// The configuration of unstyled for the crate currently being compiled.
+///
+/// It is read from the environment and from the `[package.metadata.unstyled]` table of the crate's
+/// `Cargo.toml`, where the environment takes precedence.
+///
+pub(crate) struct Config {
+    /// The directory the style fragments of all crates are written to
+    pub fragment_dir: PathBuf,
+    /// The file the merged stylesheet is written to
+    pub output: PathBuf,
+    /// The amount of base36 digits of the hash in a scope class
+    pub hash_length: usize,
+    /// Hashed together with the styles, to resolve collisions of scope classes
+    pub salt: String,
+    /// Whether scope classes contain the name of their component, defaults to debug builds
+    pub debug_names: bool,
+    /// Whether license comments (`/*! ... */`) are kept in the compiled styles
+    pub keep_license_comments: bool,
+    /// Whether nested rules are compiled to plain rules, instead of keeping the native CSS nesting
+    pub flatten_nesting: bool,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let current_dir = std::env::current_dir()
+            .map_err(|error| format!("Could not read the current directory: {error}"))?;
+        let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| current_dir.clone());
+        let metadata = read_metadata(&manifest_dir)?;
+        let target_dir = target_dir(&manifest_dir)?;
+        // Debug and release builds name their scope classes differently, so each of them keeps
+        // its own fragments, like cargo keeps its own artifacts
+        let profile = if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        };
+        let output = match (std::env::var_os(OUTPUT_ENV), metadata.get("output")) {
+            (Some(output), _) => current_dir.join(output),
+            (None, Some(output)) => manifest_dir.join(
+                output
+                    .as_str()
+                    .ok_or("package.metadata.unstyled.output has to be a string")?,
+            ),
+            (None, None) => target_dir.join("unstyled.css"),
+        };
+        let hash_length = match metadata.get("hash-length") {
+            None => hash::DEFAULT_LENGTH,
+            Some(length) => length
+                .as_integer()
+                .and_then(|length| usize::try_from(length).ok())
+                .filter(|length| (hash::MIN_LENGTH..=hash::MAX_LENGTH).contains(length))
+                .ok_or(format!(
+                    "package.metadata.unstyled.hash-length has to be a number from {} to {}",
+                    hash::MIN_LENGTH,
+                    hash::MAX_LENGTH
+                ))?,
+        };
+
+        let salt = match metadata.get("salt") {
+            None => String::new(),
+            Some(salt) => salt
+                .as_str()
+                .ok_or("package.metadata.unstyled.salt has to be a string")?
+                .to_string(),
+        };
+
+        let debug_names = match metadata.get("debug-names") {
+            None => cfg!(debug_assertions),
+            Some(debug_names) => debug_names
+                .as_bool()
+                .ok_or("package.metadata.unstyled.debug-names has to be a boolean")?,
+        };
+
+        let keep_license_comments = match metadata.get("keep-license-comments") {
+            None => false,
+            Some(keep) => keep
+                .as_bool()
+                .ok_or("package.metadata.unstyled.keep-license-comments has to be a boolean")?,
+        };
+
+        let flatten_nesting = match metadata.get("flatten-nesting") {
+            None => false,
+            Some(flatten) => flatten
+                .as_bool()
+                .ok_or("package.metadata.unstyled.flatten-nesting has to be a boolean")?,
+        };
+
+        Ok(Self {
+            fragment_dir: target_dir.join(profile).join("unstyled"),
+            output,
+            hash_length,
+            salt,
+            debug_names,
+            keep_license_comments,
+            flatten_nesting,
+        })
+    }
+}
+
+fn read_manifest(dir: &Path) -> Result<Option<Table>, String> {
+    let path = dir.join("Cargo.toml");
+
+    let Ok(manifest) = std::fs::read_to_string(&path) else {
+        return Ok(None);
+    };
+
+    manifest
+        .parse::<Table>()
+        .map(Some)
+        .map_err(|error| format!("Could not parse {}: {error}", path.display()))
+}
+
+///
+/// Reads the `[package.metadata.unstyled]` table of the crate's manifest
+///
+fn read_metadata(manifest_dir: &Path) -> Result<Table, String> {
+    let manifest = read_manifest(manifest_dir)?.unwrap_or_default();
+    let metadata = manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("unstyled"));
+
+    match metadata {
+        None => Ok(Table::new()),
+        Some(metadata) => metadata
+            .as_table()
+            .cloned()
+            .ok_or_else(|| "package.metadata.unstyled has to be a table".to_string()),
+    }
+}
+
+///
+/// Finds cargo's target directory, from the environment, the cargo config or else the `target`
+/// directory of the workspace. Relative paths in the environment are relative to the workspace
+/// root.
+///
+fn target_dir(manifest_dir: &Path) -> Result<PathBuf, String> {
+    let workspace_root = workspace_root(manifest_dir);
+
+    for variable in ["CARGO_TARGET_DIR", "CARGO_BUILD_TARGET_DIR"] {
+        if let Some(target_dir) = std::env::var_os(variable) {
+            return Ok(workspace_root.join(target_dir));
+        }
+    }
+
+    Ok(config_target_dir(manifest_dir.ancestors())?
+        .unwrap_or_else(|| workspace_root.join("target")))
+}
+
+///
+/// Reads the `build.target-dir` of the first `.cargo/config.toml` (or `.cargo/config`) in the
+/// directories, which cargo searches from the crate up to the root. It is relative to the directory
+/// containing the `.cargo` directory.
+///
+pub(crate) fn config_target_dir<'a>(
+    dirs: impl IntoIterator<Item = &'a Path>,
+) -> Result<Option<PathBuf>, String> {
+    for dir in dirs {
+        let Some((path, config)) = ["config.toml", "config"].iter().find_map(|name| {
+            let path = dir.join(".cargo").join(name);
+
+            std::fs::read_to_string(&path)
+                .ok()
+                .map(|config| (path, config))
+        }) else {
+            continue;
+        };
+        let config = config
+            .parse::<Table>()
+            .map_err(|error| format!("Could not parse {}: {error}", path.display()))?;
+
+        if let Some(target_dir) = config
+            .get("build")
+            .and_then(|build| build.get("target-dir"))
+        {
+            let target_dir = target_dir.as_str().ok_or(format!(
+                "build.target-dir in {} has to be a string",
+                path.display()
+            ))?;
+
+            return Ok(Some(dir.join(target_dir)));
+        }
+    }
+
+    Ok(None)
+}
+
+///
+/// Finds the root of the workspace the crate belongs to, which is where cargo puts the `target`
+/// directory. Crates outside of a workspace are their own root.
+///
+fn workspace_root(manifest_dir: &Path) -> PathBuf {
+    manifest_dir
+        .ancestors()
+        .find(|dir| {
+            read_manifest(dir)
+                .ok()
+                .flatten()
+                .map(|manifest| manifest.contains_key("workspace"))
+                .unwrap_or_default()
+        })
+        .unwrap_or(manifest_dir)
+        .to_path_buf()
+}
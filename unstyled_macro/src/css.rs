@@ -1,17 +1,289 @@
 use std::fmt::{Display, Formatter};
-use std::iter::Peekable;
-use std::str::Chars;
 
-const SELECTOR_END: &str = ".#[{:";
+use self::tokenizer::{Token, TokenKind};
+
+pub(crate) mod tokenizer;
+
+/// Pseudo classes, the arguments of which are selectors, which are scoped as well
+const PSEUDO_CLASSES_WITH_SELECTORS: &[&str] = &["not", "is", "where", "has"];
+
+/// At-rules, which contain style rules, the selectors of which have to be scoped
+const AT_RULES_WITH_SELECTORS: &[&str] = &["@media", "@layer", "@supports", "@container"];
+
+/// At-rules, which have to precede all other rules, in the order they have to be written in
+const LEADING_AT_RULES: &[&str] = &["@charset", "@import", "@namespace"];
+
+fn has_selectors(at_rule: &str) -> bool {
+    AT_RULES_WITH_SELECTORS
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(at_rule))
+}
+
+///
+/// A problem found while parsing or linting a stylesheet
+///
+pub(crate) struct CssError {
+    pub message: String,
+    /// Byte offset into the original stylesheet source, the error points to
+    pub offset: usize,
+}
+
+impl CssError {
+    fn new(message: impl Into<String>, offset: usize) -> Self {
+        Self {
+            message: message.into(),
+            offset,
+        }
+    }
+}
 
 struct AtRuleWithSelectors {
     at_rule: String,
-    blocks: Vec<NormalBlock>,
+    /// The content of the at-rule, which can contain further at-rules, like `@media` in `@layer`
+    blocks: Vec<StyleBlock>,
+}
+
+/// A complex selector like `.a > .b`, the last combinator of which is always a descendant one
+type ComplexSelector = Vec<Combinator>;
+
+///
+/// A selector in the arguments of a pseudo class like `:not()`. In `:has()` it can start with a
+/// combinator, like `> .a`, which relates it to the element matched by the `:has()`.
+///
+#[derive(Clone)]
+struct RelativeSelector {
+    combinator: Option<char>,
+    selector: ComplexSelector,
+}
+
+impl RelativeSelector {
+    fn compile(&self, scope_class: &str) -> String {
+        let selector = self
+            .selector
+            .iter()
+            .map(|combinator| combinator.compile(scope_class))
+            .collect::<String>();
+
+        format!(
+            "{}{}",
+            self.combinator.map(String::from).unwrap_or_default(),
+            selector.trim_end()
+        )
+    }
+}
+
+impl RelativeSelector {
+    ///
+    /// Resolves the selector of a nested rule against the selector list of its parent rule. The
+    /// parent replaces every `&`, also the ones in pseudo classes like `:not(&)`, or the selector
+    /// is combined with it like a descendant (or with its own combinator) when there is none.
+    ///
+    /// Each `&` stands for any of the parent selectors, so `& + &` resolves to every combination of
+    /// them.
+    ///
+    fn resolve(&self, parents: &[RelativeSelector]) -> Vec<RelativeSelector> {
+        let count = count_nesting(&self.selector);
+
+        if count == 0 {
+            let combinator = match self.combinator {
+                Some('>') => Combinator::Child(Selector::Nesting),
+                Some('+') => Combinator::Sibling(Selector::Nesting),
+                Some('~') => Combinator::General(Selector::Nesting),
+                _ => Combinator::Descendant(Selector::Nesting),
+            };
+
+            return parents
+                .iter()
+                .map(|parent| {
+                    let mut selector = splice(&parent.selector, &combinator, Vec::new());
+                    selector.extend(self.selector.iter().cloned());
+
+                    RelativeSelector {
+                        combinator: parent.combinator,
+                        selector,
+                    }
+                })
+                .collect();
+        }
+
+        let combinations = parents.len().pow(count as u32);
+
+        (0..combinations)
+            .map(|combination| {
+                // The parent of the first `&` changes the slowest
+                let chosen = (0..count as u32)
+                    .rev()
+                    .map(|index| &parents[combination / parents.len().pow(index) % parents.len()])
+                    .collect::<Vec<_>>();
+                let mut selectors = chosen.iter().map(|parent| &parent.selector);
+
+                RelativeSelector {
+                    combinator: chosen[0].combinator,
+                    selector: replace_nesting(&self.selector, &mut selectors),
+                }
+            })
+            .collect()
+    }
+}
+
+///
+/// Counts the `&` of the selector, including the ones in the arguments of pseudo classes like
+/// `:not(&)`
+///
+fn count_nesting(selector: &ComplexSelector) -> usize {
+    selector
+        .iter()
+        .map(|combinator| match combinator.selector() {
+            Selector::Nesting => 1,
+            Selector::FunctionalPseudo(_, arguments) => arguments
+                .iter()
+                .map(|argument| count_nesting(&argument.selector))
+                .sum(),
+            _ => 0,
+        })
+        .sum()
+}
+
+///
+/// Replaces each `&` of the selector with the next of the parent selectors, including the ones
+/// in the arguments of pseudo classes
+///
+fn replace_nesting<'a>(
+    selector: &ComplexSelector,
+    parents: &mut impl Iterator<Item = &'a ComplexSelector>,
+) -> ComplexSelector {
+    let mut replaced = Vec::new();
+
+    for combinator in selector {
+        match combinator.selector() {
+            Selector::Nesting => {
+                let parent = parents.next().expect("Has a parent for every &");
+                // The simple selectors written right before the `&`, like the `h2` of `h2&`
+                let start = replaced
+                    .iter()
+                    .rposition(|combinator| !matches!(combinator, Combinator::Combine(_)))
+                    .map_or(0, |index| index + 1);
+                let prefix = replaced.split_off(start);
+
+                replaced.extend(splice(parent, combinator, prefix));
+            }
+            Selector::FunctionalPseudo(name, arguments) => {
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| RelativeSelector {
+                        combinator: argument.combinator,
+                        selector: replace_nesting(&argument.selector, parents),
+                    })
+                    .collect();
+                let pseudo = Selector::FunctionalPseudo(name.clone(), arguments);
+
+                replaced.push(combinator.with_selector(pseudo));
+            }
+            _ => replaced.push(combinator.clone()),
+        }
+    }
+
+    replaced
+}
+
+///
+/// Returns the parent selector, the last compound selector of which is merged with the simple
+/// selectors of the `prefix` and followed by the given combinator instead of its own
+///
+fn splice(
+    parent: &ComplexSelector,
+    combinator: &Combinator,
+    prefix: Vec<Combinator>,
+) -> ComplexSelector {
+    let is_type = |combinator: &Combinator| {
+        matches!(
+            combinator.selector(),
+            Selector::Tag(..) | Selector::Universal(_)
+        )
+    };
+    // The last compound starts after the last simple selector not combined with the next one
+    let start = parent
+        .iter()
+        .rev()
+        .skip(1)
+        .position(|combinator| !matches!(combinator, Combinator::Combine(_)))
+        .map_or(0, |position| parent.len() - 1 - position);
+    let mut selector = parent[..start].to_vec();
+    let mut compound = parent[start..].to_vec();
+    let types = prefix
+        .iter()
+        .take_while(|combinator| is_type(combinator))
+        .count();
+
+    // A compound selector has a single type selector, which has to come first. The compound of
+    // the parent is matched by `:is()` instead, when both of them have one.
+    if types > 0 && compound.first().is_some_and(is_type) {
+        if let Some(last) = compound.pop() {
+            compound.push(Combinator::Descendant(last.selector().clone()));
+        }
+
+        let argument = RelativeSelector {
+            combinator: None,
+            selector: compound,
+        };
+        compound = vec![Combinator::Combine(Selector::FunctionalPseudo(
+            "is".to_string(),
+            vec![argument],
+        ))];
+    }
+
+    let mut merged = prefix[..types]
+        .iter()
+        .chain(&compound)
+        .chain(&prefix[types..])
+        .map(|combinator| Combinator::Combine(combinator.selector().clone()))
+        .collect::<Vec<_>>();
+
+    if let Some(last) = merged.pop() {
+        merged.push(combinator.with_selector(last.selector().clone()));
+    }
+
+    selector.extend(merged);
+
+    selector
+}
+
+impl Display for RelativeSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let selector = self
+            .selector
+            .iter()
+            .map(|combinator| combinator.to_string())
+            .collect::<String>();
+
+        if let Some(combinator) = self.combinator {
+            write!(f, "{combinator}")?;
+        }
+
+        f.write_str(selector.trim_end())
+    }
 }
 
 struct NormalBlock {
-    selector: Vec<Combinator>,
-    content: String,
+    /// The selector list, like `.a, .b > .c`. Selectors of nested rules can start with a
+    /// combinator, like `> .a`.
+    selectors: Vec<RelativeSelector>,
+    /// The content of the block, without its braces
+    items: Vec<BlockItem>,
+}
+
+enum BlockItem {
+    /// Declarations like `color: red;`, as well as nested at-rules without selectors, which are
+    /// written as is
+    Declarations(String),
+    /// A nested rule like `&:hover { ... }`
+    Rule(NormalBlock),
+    /// A nested at-rule like `@media print { ... }`, containing declarations applying to the
+    /// parent rule, as well as nested rules
+    AtRule {
+        at_rule: String,
+        items: Vec<BlockItem>,
+    },
 }
 
 enum StyleBlock {
@@ -20,12 +292,10 @@ enum StyleBlock {
     AtRuleWithSelectors(AtRuleWithSelectors),
     /// Used for generic/other @rules like @keyframes, @page, @import etc.
     GenericAtRule(String),
-}
-
-impl StyleBlock {
-    pub fn is_normal(&self) -> bool {
-        matches!(self, Self::Normal(_))
-    }
+    /// A `@namespace` rule, declaring a prefix like the `svg` of `svg|rect`
+    Namespace(String),
+    /// A license comment (`/*! ... */`), which is kept in the output
+    Comment(String),
 }
 
 #[derive(Default)]
@@ -53,95 +323,239 @@ impl AtRuleWithSelectors {
     }
 }
 
+///
+/// Checks the declarations of a block, like `color: red;`, for obvious mistakes
+///
 #[cfg(feature = "css-block-lint")]
-enum ScanningKind {
-    Whitespace,
-    Name,
-    Value,
-}
+fn check_css_block_syntax(source: &str, content: &[Token]) -> Vec<CssError> {
+    let mut errors = Vec::new();
+    let mut start = 0;
 
-#[cfg(feature = "css-block-lint")]
-fn check_css_block_syntax(content: &str) {
-    let chars = content.chars();
-    let mut quoted = false;
-    let mut scanning_kind = ScanningKind::Whitespace;
-    let mut statement = String::new();
-    let mut scanned = String::new();
-
-    for char in chars {
-        if "{}".contains(char) {
-            continue;
-        }
+    while start < content.len() {
+        let end = find_top_level(content, start, |kind| *kind == TokenKind::Semicolon)
+            .unwrap_or(content.len());
+        let declaration = trim_whitespace(&content[start..end]);
+        start = end + 1;
 
-        if "'\"".contains(char) {
-            quoted = !quoted;
-            scanned.push(char);
-            statement.push(char);
+        let Some(first) = declaration.first() else {
+            continue;
+        };
+        let Some(colon) = find_top_level(declaration, 0, |kind| *kind == TokenKind::Colon) else {
             continue;
+        };
+        let statement = tokens_to_css(source, declaration);
+        let name = trim_whitespace(&declaration[..colon]);
+        let value = trim_whitespace(&declaration[colon + 1..]);
+
+        if !matches!(
+            name,
+            [Token {
+                kind: TokenKind::Ident,
+                ..
+            }]
+        ) {
+            errors.push(CssError::new(
+                format!(
+                    "Property \"{}\" seems invalid!",
+                    tokens_to_css(source, name)
+                ),
+                first.span.start,
+            ));
+        } else if find_top_level(value, 0, |kind| *kind == TokenKind::Colon).is_some() {
+            errors.push(CssError::new(
+                format!("Statement \"{statement}\" seems unterminated!"),
+                first.span.start,
+            ));
+        } else if value.is_empty() {
+            errors.push(CssError::new(
+                format!("Statement \"{statement}\" is missing a value!"),
+                first.span.start,
+            ));
         }
+    }
 
-        if matches!(scanning_kind, ScanningKind::Whitespace) && !char.is_whitespace() {
-            scanning_kind = ScanningKind::Name;
-        }
+    errors
+}
 
-        if matches!(scanning_kind, ScanningKind::Value) && char == ':' && !quoted {
-            panic!("Statement \"{statement}\" seems unterminated!");
+impl BlockItem {
+    fn compile(&self, scope_class: &str) -> String {
+        match self {
+            BlockItem::Declarations(declarations) => declarations.clone(),
+            BlockItem::Rule(rule) => rule.compile(scope_class),
+            BlockItem::AtRule { at_rule, items } => {
+                let items = items
+                    .iter()
+                    .map(|item| item.compile(scope_class))
+                    .collect::<String>();
+
+                format!("{at_rule}{{{items}}}")
+            }
         }
+    }
+}
 
-        if matches!(scanning_kind, ScanningKind::Name) && char == ':' && !quoted {
-            for char in scanned.trim().chars() {
-                // CSS this is allowed as of the ident spec (https://www.w3.org/TR/CSS22/syndata.html#value-def-identifier). Yes emojis are fine
-                if "-_".contains(char) || char.is_alphanumeric() || char > '\u{0080}' {
-                    continue;
+impl Display for BlockItem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockItem::Declarations(declarations) => f.write_str(declarations),
+            BlockItem::Rule(rule) => rule.fmt(f),
+            BlockItem::AtRule { at_rule, items } => {
+                f.write_str(at_rule)?;
+                f.write_str("{")?;
+
+                for item in items {
+                    item.fmt(f)?;
                 }
 
-                panic!("Property \"{scanned}\" seems invalid!")
+                f.write_str("}")
             }
-
-            scanning_kind = ScanningKind::Value;
-            scanned.clear();
-            statement.push(char);
-            continue;
         }
+    }
+}
 
-        if !matches!(scanning_kind, ScanningKind::Whitespace) && (!quoted && char != ';') {
-            scanned.push(char);
-            statement.push(char);
+impl NormalBlock {
+    pub fn compile(&self, scope_class: &str) -> String {
+        let selectors = self
+            .selectors
+            .iter()
+            .map(|selector| selector.compile(scope_class));
+        let items = self
+            .items
+            .iter()
+            .map(|item| item.compile(scope_class))
+            .collect::<String>();
+
+        format!("{}{{{items}}}", join_selectors(selectors))
+    }
+
+    ///
+    /// Replaces the nested rules with plain rules following this one, the selectors of which are
+    /// resolved against the selectors of this rule. Nested at-rules are moved out of the rule,
+    /// containing a copy of it for their declarations.
+    ///
+    fn flatten(self) -> Vec<StyleBlock> {
+        if self
+            .items
+            .iter()
+            .all(|item| matches!(item, BlockItem::Declarations(_)))
+        {
+            return vec![StyleBlock::Normal(self)];
         }
 
-        if char == ';' && !quoted {
-            scanning_kind = ScanningKind::Whitespace;
+        let mut declarations = Vec::new();
+        let mut nested = Vec::new();
 
-            if scanned.trim().is_empty() {
-                panic!("Statement \"{statement}\" is missing a value!");
+        for item in self.items {
+            match item {
+                BlockItem::Declarations(content) if !content.trim().is_empty() => {
+                    declarations.push(content.trim().to_string());
+                }
+                BlockItem::Declarations(_) => {}
+                item => nested.push(item),
             }
+        }
 
-            statement.clear();
-            scanned.clear();
+        let mut blocks = Vec::new();
 
-            continue;
+        if !declarations.is_empty() {
+            blocks.push(StyleBlock::Normal(NormalBlock {
+                selectors: self.selectors.clone(),
+                items: vec![BlockItem::Declarations(format!(
+                    " {} ",
+                    declarations.join(" ")
+                ))],
+            }));
         }
-    }
 
-    if quoted {
-        panic!("{content} is missing closing quote!");
+        for item in nested {
+            match item {
+                BlockItem::Rule(rule) => {
+                    let selectors = rule
+                        .selectors
+                        .iter()
+                        .flat_map(|nested| nested.resolve(&self.selectors))
+                        .collect();
+                    let rule = NormalBlock {
+                        selectors,
+                        items: rule.items,
+                    };
+
+                    blocks.extend(rule.flatten());
+                }
+                BlockItem::AtRule { at_rule, items } => {
+                    let rule = NormalBlock {
+                        selectors: self.selectors.clone(),
+                        items,
+                    };
+
+                    blocks.push(StyleBlock::AtRuleWithSelectors(AtRuleWithSelectors {
+                        at_rule,
+                        blocks: rule.flatten(),
+                    }));
+                }
+                BlockItem::Declarations(_) => {}
+            }
+        }
+
+        blocks
     }
 }
 
-impl NormalBlock {
-    pub fn compile(&self, scope_class: &str) -> String {
-        let mut output = String::new();
+///
+/// Joins the selectors of a selector list with commas, followed by the space separating them from
+/// the block
+///
+fn join_selectors(selectors: impl Iterator<Item = String>) -> String {
+    let mut output = selectors
+        .map(|selector| selector.trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    if !output.is_empty() {
+        output.push(' ');
+    }
 
-        for combinator in &self.selector {
-            output.push_str(&combinator.compile(scope_class));
-        }
+    output
+}
 
-        #[cfg(feature = "css-block-lint")]
-        check_css_block_syntax(&self.content);
+///
+/// Removes the whitespace next to braces and semicolons of a block, which is never significant
+///
+fn trim_block_whitespace(content: &str) -> String {
+    let tokens = tokenizer::tokenize(content, false).tokens;
+    let is_separator = |token: Option<&Token>| {
+        token.is_some_and(|token| {
+            matches!(
+                token.kind,
+                TokenKind::OpenCurly | TokenKind::CloseCurly | TokenKind::Semicolon
+            )
+        })
+    };
+
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(index, token)| {
+            let previous = index.checked_sub(1).and_then(|index| tokens.get(index));
+
+            !token.is_whitespace()
+                || !(is_separator(previous) || is_separator(tokens.get(index + 1)))
+        })
+        .map(|(_, token)| token.text(content))
+        .collect()
+}
 
-        output.push_str(&self.content);
+impl Display for NormalBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let selectors = self.selectors.iter().map(|selector| selector.to_string());
+        let items = self
+            .items
+            .iter()
+            .map(|item| item.to_string())
+            .collect::<String>();
 
-        output
+        f.write_str(&join_selectors(selectors))?;
+        f.write_str(&trim_block_whitespace(&format!("{{{items}}}")))
     }
 }
 
@@ -156,7 +570,9 @@ impl StyleBlock {
             StyleBlock::Normal(block) => {
                 output.push_str(&block.compile(scope_class));
             }
-            StyleBlock::GenericAtRule(content) => {
+            StyleBlock::GenericAtRule(content)
+            | StyleBlock::Namespace(content)
+            | StyleBlock::Comment(content) => {
                 output.push_str(content);
             }
         }
@@ -165,6 +581,29 @@ impl StyleBlock {
     }
 }
 
+impl Display for StyleBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StyleBlock::AtRuleWithSelectors(at_rule) => {
+                f.write_str(&at_rule.at_rule)?;
+                f.write_str("{")?;
+
+                for block in &at_rule.blocks {
+                    block.fmt(f)?;
+                }
+
+                f.write_str("}")
+            }
+            StyleBlock::Normal(block) => block.fmt(f),
+            StyleBlock::GenericAtRule(content) | StyleBlock::Namespace(content) => {
+                f.write_str(content)
+            }
+            // Comments don't change the style
+            StyleBlock::Comment(_) => Ok(()),
+        }
+    }
+}
+
 impl Stylesheet {
     pub fn compile(&self, scope_class: &str) -> String {
         let mut output = String::new();
@@ -175,13 +614,53 @@ impl Stylesheet {
 
         output
     }
+
+    ///
+    /// Replaces nested rules (CSS nesting) with plain rules, for browsers not supporting them
+    ///
+    pub fn flatten_nesting(&mut self) {
+        self.blocks = flatten_blocks(std::mem::take(&mut self.blocks));
+    }
+}
+
+fn flatten_blocks(blocks: Vec<StyleBlock>) -> Vec<StyleBlock> {
+    let mut flattened = Vec::new();
+
+    for block in blocks {
+        match block {
+            StyleBlock::Normal(block) => flattened.extend(block.flatten()),
+            StyleBlock::AtRuleWithSelectors(mut at_rule) => {
+                at_rule.blocks = flatten_blocks(at_rule.blocks);
+                flattened.push(StyleBlock::AtRuleWithSelectors(at_rule));
+            }
+            block => flattened.push(block),
+        }
+    }
+
+    flattened
 }
 
+///
+/// Writes the stylesheet without any scope classes. As it is written from the parsed stylesheet, it
+/// is independent of the formatting of the source, which makes it the input for the scope class.
+///
+impl Display for Stylesheet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for block in &self.blocks {
+            block.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
 enum Combinator {
     Sibling(Selector),
     Child(Selector),
     General(Selector),
-    Namespace(Selector),
+    /// The `||` column combinator
+    Column(Selector),
     Descendant(Selector),
     Combine(Selector),
 }
@@ -231,6 +710,14 @@ fn apply_scope_class(scope_class: &str, combinator: &str, selector: &Selector) -
 
             format!(".{scope_class}{selector}{combinator}")
         }
+        Selector::FunctionalPseudo(..) => {
+            format!(
+                ".{scope_class}{}{combinator}",
+                selector.compile(scope_class)
+            )
+        }
+        // The parent rule is scoped already
+        Selector::Nesting => format!("{selector}{combinator}"),
         selector => format!("{selector}.{scope_class}{combinator}"),
     }
 }
@@ -241,54 +728,230 @@ impl Combinator {
             Combinator::Sibling(selector) => apply_scope_class(scope_class, "+", selector),
             Combinator::Child(selector) => apply_scope_class(scope_class, ">", selector),
             Combinator::General(selector) => apply_scope_class(scope_class, "~", selector),
-            Combinator::Namespace(selector) => apply_scope_class(scope_class, "|", selector),
+            Combinator::Column(selector) => apply_scope_class(scope_class, "||", selector),
             Combinator::Descendant(selector) => apply_scope_class(scope_class, " ", selector),
-            Combinator::Combine(selector) => format!("{selector}"),
+            Combinator::Combine(selector) => selector.compile(scope_class),
+        }
+    }
+
+    fn selector(&self) -> &Selector {
+        match self {
+            Combinator::Sibling(selector)
+            | Combinator::Child(selector)
+            | Combinator::General(selector)
+            | Combinator::Column(selector)
+            | Combinator::Descendant(selector)
+            | Combinator::Combine(selector) => selector,
+        }
+    }
+
+    ///
+    /// Returns the same kind of combinator for another selector
+    ///
+    fn with_selector(&self, selector: Selector) -> Combinator {
+        match self {
+            Combinator::Sibling(_) => Combinator::Sibling(selector),
+            Combinator::Child(_) => Combinator::Child(selector),
+            Combinator::General(_) => Combinator::General(selector),
+            Combinator::Column(_) => Combinator::Column(selector),
+            Combinator::Descendant(_) => Combinator::Descendant(selector),
+            Combinator::Combine(_) => Combinator::Combine(selector),
         }
     }
 }
 
+impl Display for Combinator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Combinator::Sibling(selector) => write!(f, "{selector}+"),
+            Combinator::Child(selector) => write!(f, "{selector}>"),
+            Combinator::General(selector) => write!(f, "{selector}~"),
+            Combinator::Column(selector) => write!(f, "{selector}||"),
+            Combinator::Descendant(selector) => write!(f, "{selector} "),
+            Combinator::Combine(selector) => selector.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone)]
 enum Selector {
-    Tag(String),
+    /// The `&` of a nested rule, referring to the elements matched by its parent rule
+    Nesting,
+    /// A type selector with its optional namespace prefix, like `svg|rect`, which is empty for `|a`
+    Tag(Option<String>, String),
+    /// The universal selector `*`, with its optional namespace prefix, like `svg|*`
+    Universal(Option<String>),
     Class(String),
     Id(String),
-    Attribute(String),
+    Attribute(AttributeSelector),
     Pseudo(String),
+    /// A pseudo class taking selectors, like `:not(.a, .b)`, with its name and arguments
+    FunctionalPseudo(String, Vec<RelativeSelector>),
+}
+
+///
+/// An attribute selector like `[lang|=en]` or `[ns|title~="a b" i]`
+///
+#[derive(Clone)]
+struct AttributeSelector {
+    /// The namespace prefix, which is empty for `[|name]`
+    namespace: Option<String>,
+    name: String,
+    /// The operator (`=`, `~=`, `|=`, `^=`, `$=` or `*=`) and the value, as written in the source
+    matcher: Option<(String, String)>,
+    /// The `i` or `s` flag, controlling the case sensitivity of the value
+    modifier: Option<String>,
+}
+
+impl Display for AttributeSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+
+        if let Some(namespace) = &self.namespace {
+            write!(f, "{namespace}|")?;
+        }
+
+        f.write_str(&self.name)?;
+
+        if let Some((operator, value)) = &self.matcher {
+            write!(f, "{operator}{value}")?;
+        }
+
+        if let Some(modifier) = &self.modifier {
+            write!(f, " {modifier}")?;
+        }
+
+        f.write_str("]")
+    }
+}
+
+///
+/// Parses the content of an attribute selector, which is everything between its brackets
+///
+fn parse_attribute(source: &str, tokens: &[Token]) -> Option<AttributeSelector> {
+    let tokens = tokens
+        .iter()
+        .filter(|token| !token.is_whitespace())
+        .collect::<Vec<_>>();
+    let mut index = 0;
+
+    let namespace = match tokens.as_slice() {
+        [prefix, separator, name, ..]
+            if matches!(prefix.kind, TokenKind::Ident | TokenKind::Delim('*'))
+                && separator.kind == TokenKind::Delim('|')
+                && name.kind == TokenKind::Ident =>
+        {
+            index = 2;
+
+            Some(prefix.text(source).to_string())
+        }
+        [separator, name, ..]
+            if separator.kind == TokenKind::Delim('|') && name.kind == TokenKind::Ident =>
+        {
+            index = 1;
+
+            Some(String::new())
+        }
+        _ => None,
+    };
+
+    let name = tokens
+        .get(index)
+        .filter(|token| token.kind == TokenKind::Ident)?
+        .text(source)
+        .to_string();
+    index += 1;
+
+    let operator = match (tokens.get(index), tokens.get(index + 1)) {
+        (None, _) => {
+            return Some(AttributeSelector {
+                namespace,
+                name,
+                matcher: None,
+                modifier: None,
+            })
+        }
+        (Some(equals), _) if equals.kind == TokenKind::Delim('=') => {
+            index += 1;
+
+            "="
+        }
+        (Some(operator), Some(equals))
+            if matches!(operator.kind, TokenKind::Delim('~' | '|' | '^' | '$' | '*'))
+                && equals.kind == TokenKind::Delim('=')
+                && operator.span.end == equals.span.start =>
+        {
+            index += 2;
+
+            &source[operator.span.start..equals.span.end]
+        }
+        _ => return None,
+    };
+
+    let value = tokens
+        .get(index)
+        .filter(|token| matches!(token.kind, TokenKind::Ident | TokenKind::String))?
+        .text(source)
+        .to_string();
+    index += 1;
+
+    let modifier = match tokens.get(index) {
+        None => None,
+        Some(modifier)
+            if modifier.kind == TokenKind::Ident
+                && ["i", "s"]
+                    .iter()
+                    .any(|flag| flag.eq_ignore_ascii_case(modifier.text(source))) =>
+        {
+            index += 1;
+
+            Some(modifier.text(source).to_string())
+        }
+        Some(_) => return None,
+    };
+
+    if index < tokens.len() {
+        return None;
+    }
+
+    Some(AttributeSelector {
+        namespace,
+        name,
+        matcher: Some((operator.to_string(), value)),
+        modifier,
+    })
 }
 
 impl Selector {
-    fn push(&mut self, char: char) {
+    ///
+    /// Writes the selector, scoping the selectors in its arguments
+    ///
+    fn compile(&self, scope_class: &str) -> String {
         match self {
-            Selector::Tag(content) => {
-                content.push(char);
-            }
-            Selector::Class(content) => {
-                content.push(char);
-            }
-            Selector::Id(content) => {
-                content.push(char);
-            }
-            Selector::Attribute(content) => {
-                content.push(char);
-            }
-            Selector::Pseudo(content) => {
-                content.push(char);
+            Selector::FunctionalPseudo(name, arguments) => {
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| argument.compile(scope_class))
+                    .collect::<Vec<_>>();
+
+                format!(":{name}({})", arguments.join(","))
             }
+            selector => selector.to_string(),
         }
     }
-
-    fn is_attribute(&self) -> bool {
-        matches!(self, Self::Attribute(_))
-    }
 }
 
 impl Display for Selector {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Selector::Tag(selector) => f.write_str(selector),
+            Selector::Nesting => f.write_str("&"),
+            Selector::Tag(Some(namespace), name) => write!(f, "{namespace}|{name}"),
+            Selector::Tag(None, name) => f.write_str(name),
+            Selector::Universal(Some(namespace)) => write!(f, "{namespace}|*"),
+            Selector::Universal(None) => f.write_str("*"),
             Selector::Class(selector) => f.write_fmt(format_args!(".{selector}")),
             Selector::Id(selector) => f.write_fmt(format_args!("#{selector}")),
-            Selector::Attribute(selector) => f.write_fmt(format_args!("[{selector}]")),
+            Selector::Attribute(selector) => selector.fmt(f),
             Selector::Pseudo(selector) => {
                 let mut pseudo_colon = ":";
                 for element in PSEUDO_ELEMENTS {
@@ -300,326 +963,772 @@ impl Display for Selector {
 
                 f.write_fmt(format_args!("{pseudo_colon}{selector}"))
             }
+            Selector::FunctionalPseudo(name, arguments) => {
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| argument.to_string())
+                    .collect::<Vec<_>>();
+
+                write!(f, ":{name}({})", arguments.join(","))
+            }
         }
     }
 }
 
-#[derive(Default)]
-pub(crate) struct StylesheetParser {
-    pub stylesheet: Stylesheet,
-    current_selector: Vec<Combinator>,
+///
+/// Writes the source of the tokens, with each whitespace collapsed to a single space
+///
+fn tokens_to_css(source: &str, tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| match token.is_whitespace() {
+            true => " ",
+            false => token.text(source),
+        })
+        .collect()
 }
 
-impl StylesheetParser {
-    pub fn compress_combinator(&self, css: String, combinator: &str) -> String {
-        css.replace(&[" ", combinator, " "].join(""), combinator)
-            .replace(&[combinator, " "].join(""), combinator)
-            .replace(&[" ", combinator].join(""), combinator)
+///
+/// Writes the source of selector tokens the way selectors are normalized: without the whitespace
+/// around combinators and with single colons for pseudo elements
+///
+fn selector_to_css(source: &str, tokens: &[Token]) -> String {
+    let tokens = trim_whitespace(tokens);
+    let is_combinator = |token: Option<&Token>| {
+        token.is_some_and(|token| matches!(token.kind, TokenKind::Delim('>' | '+' | '~' | '|')))
+    };
+    let mut output = String::new();
+
+    for (index, token) in tokens.iter().enumerate() {
+        let previous = index.checked_sub(1).and_then(|index| tokens.get(index));
+
+        match token.kind {
+            TokenKind::Whitespace
+                if is_combinator(previous) || is_combinator(tokens.get(index + 1)) => {}
+            TokenKind::Whitespace => output.push(' '),
+            TokenKind::Colon if previous.is_some_and(|token| token.kind == TokenKind::Colon) => {}
+            _ => output.push_str(token.text(source)),
+        }
     }
 
-    pub fn parse_stylesheet(&mut self, css: String) {
-        let css = css
-            .split_whitespace()
-            .collect::<Vec<_>>()
-            .join(" ")
-            .replace("::", ":");
-        let css = self.compress_combinator(css, ">");
-        let css = self.compress_combinator(css, "+");
-        let css = self.compress_combinator(css, "|");
-        let css = self.compress_combinator(css, "~");
-        let mut char_iter = css.chars().peekable();
-        let mut in_block = 0;
-        let mut block_tokens = String::new();
-
-        loop {
-            let Some(char) = char_iter.peek().copied() else {
-                break;
-            };
+    output
+}
 
-            if char == '{' {
-                in_block += 1;
-            }
+fn trim_whitespace(tokens: &[Token]) -> &[Token] {
+    let start = tokens
+        .iter()
+        .position(|token| !token.is_whitespace())
+        .unwrap_or(tokens.len());
+    let end = tokens
+        .iter()
+        .rposition(|token| !token.is_whitespace())
+        .map_or(start, |end| end + 1);
+
+    &tokens[start..end]
+}
 
-            if char == '}' {
-                in_block -= 1;
+///
+/// Finds the first token from `start` on matching the predicate, which is not nested in a block
+///
+fn find_top_level(
+    tokens: &[Token],
+    start: usize,
+    predicate: impl Fn(&TokenKind) -> bool,
+) -> Option<usize> {
+    let mut depth = 0_usize;
+
+    for (index, token) in tokens.iter().enumerate().skip(start) {
+        if depth == 0 && predicate(&token.kind) {
+            return Some(index);
+        }
 
-                if in_block == 0 {
-                    block_tokens.push(char);
+        if token.opens_block() {
+            depth += 1;
+        } else if token.closes_block() {
+            depth = depth.saturating_sub(1);
+        }
+    }
 
-                    let selector = std::mem::take(&mut self.current_selector);
+    None
+}
 
-                    let block = NormalBlock {
-                        selector,
-                        content: block_tokens,
-                    };
+///
+/// Finds the token closing the block, which is opened by the token at `open`
+///
+fn block_end(tokens: &[Token], open: usize) -> Option<usize> {
+    let mut depth = 0_usize;
 
-                    self.stylesheet.blocks.push(StyleBlock::Normal(block));
+    for (index, token) in tokens.iter().enumerate().skip(open) {
+        if token.opens_block() {
+            depth += 1;
+        } else if token.closes_block() {
+            depth -= 1;
 
-                    block_tokens = String::new();
-                }
+            if depth == 0 {
+                return Some(index);
             }
+        }
+    }
 
-            if in_block > 0 {
-                block_tokens.push(char);
-                char_iter.next();
+    None
+}
 
-                continue;
-            }
+///
+/// The position of the at-rule in [`LEADING_AT_RULES`], if it is one of them
+///
+fn leading_rank(rule: &str) -> Option<usize> {
+    let tokens = tokenizer::tokenize(rule, false).tokens;
+    let Some(TokenKind::AtKeyword(name)) = tokens.first().map(|token| &token.kind) else {
+        return None;
+    };
+
+    LEADING_AT_RULES
+        .iter()
+        .position(|leading| leading.eq_ignore_ascii_case(name))
+}
 
-            if ".#:[]_".contains(char) || char.is_alphabetic() {
-                self.parse_selector(&mut char_iter);
+///
+/// Splits the leading `@charset`, `@import` and `@namespace` rules off of compiled css. As they
+/// are only valid before all other rules, the merged stylesheet starts with the ones of all styles.
+///
+pub(crate) fn split_leading_rules(css: &str) -> (Vec<String>, String) {
+    let tokens = tokenizer::tokenize(css, true).tokens;
+    let mut rules = Vec::new();
+    let mut rest = String::new();
+    let mut copied = 0;
+    let mut index = 0;
+
+    while let Some(token) = tokens.get(index) {
+        match &token.kind {
+            TokenKind::Whitespace | TokenKind::Comment => index += 1,
+            TokenKind::AtKeyword(name)
+                if LEADING_AT_RULES
+                    .iter()
+                    .any(|leading| leading.eq_ignore_ascii_case(name)) =>
+            {
+                let Some(end) =
+                    find_top_level(&tokens, index, |kind| *kind == TokenKind::Semicolon)
+                else {
+                    break;
+                };
 
-                continue;
+                rest.push_str(&css[copied..token.span.start]);
+                rules.push(css[token.span.start..tokens[end].span.end].to_string());
+                copied = tokens[end].span.end;
+                index = end + 1;
             }
+            _ => break,
+        }
+    }
 
-            if char == '@' {
-                self.parse_at_rule(&mut char_iter);
+    rest.push_str(&css[copied..]);
 
-                continue;
-            }
+    (rules, rest)
+}
 
-            char_iter.next();
-        }
+///
+/// Reads the prefix, which is empty for the default namespace, and the url of a `@namespace` rule.
+/// The url is unquoted, so `url(a)` and `"a"` are the same.
+///
+pub(crate) fn parse_namespace_rule(rule: &str) -> Option<(String, String)> {
+    let tokens = tokenizer::tokenize(rule, false).tokens;
+    let mut tokens = tokens.iter().filter(|token| !token.is_whitespace());
+
+    match &tokens.next()?.kind {
+        TokenKind::AtKeyword(name) if name.eq_ignore_ascii_case("@namespace") => {}
+        _ => return None,
     }
 
-    pub fn parse_selector(&mut self, char_iter: &mut Peekable<Chars>) {
-        let mut current_token = None;
-        let mut in_braces = 0;
+    let mut token = tokens.next()?;
+    let mut prefix = String::new();
 
-        loop {
-            let Some(char) = char_iter.peek().copied() else {
-                break;
-            };
+    if token.kind == TokenKind::Ident {
+        prefix = token.text(rule).to_string();
+        token = tokens.next()?;
+    }
 
-            if current_token.is_none() {
-                if char == '.' {
-                    char_iter.next();
-                    current_token = Some(Selector::Class(String::new()));
+    let unquote = |token: &Token| {
+        let text = token.text(rule);
 
-                    continue;
-                }
+        text.get(1..text.len().saturating_sub(1))
+            .map(str::to_string)
+    };
+    let url = match token.kind {
+        TokenKind::String => unquote(token)?,
+        TokenKind::Url => {
+            let text = token.text(rule);
 
-                if char == '#' {
-                    char_iter.next();
-                    current_token = Some(Selector::Id(String::new()));
+            text.get(4..text.len().saturating_sub(1))?
+                .trim()
+                .to_string()
+        }
+        TokenKind::Function => unquote(tokens.next().filter(|url| url.kind == TokenKind::String)?)?,
+        _ => return None,
+    };
 
-                    continue;
-                }
+    Some((prefix, url))
+}
 
-                if char == ':' {
-                    char_iter.next();
-                    current_token = Some(Selector::Pseudo(String::new()));
+///
+/// Orders the leading rules of multiple styles like CSS requires it, `@charset` first and
+/// `@namespace` last, keeping the order of the rules of the same kind
+///
+pub(crate) fn sort_leading_rules(rules: &mut [String]) {
+    rules.sort_by_key(|rule| leading_rank(rule));
+}
 
-                    continue;
-                }
+#[derive(Default)]
+pub(crate) struct StylesheetParser {
+    pub stylesheet: Stylesheet,
+    pub errors: Vec<CssError>,
+    /// Keeps license comments (`/*! ... */`) between rules, all other comments are dropped
+    pub keep_license_comments: bool,
+    /// Parses a global style, which may declare the default namespace. It would apply to the type
+    /// selectors of all styles sharing the merged stylesheet.
+    pub global: bool,
+    /// The source ended inside of a string, comment or url, which was reported already. The
+    /// blocks left open by it are not reported again.
+    truncated: bool,
+}
 
-                if char == '[' {
-                    char_iter.next();
-                    current_token = Some(Selector::Attribute(String::new()));
+impl StylesheetParser {
+    pub fn parse_stylesheet(&mut self, css: String) {
+        let tokens = tokenizer::tokenize(&css, self.keep_license_comments);
 
-                    continue;
-                }
+        self.errors.extend(tokens.errors);
+        self.truncated = tokens.truncated;
+        self.parse_tokens(&css, &tokens.tokens);
+    }
 
-                if char.is_alphabetic() {
-                    char_iter.next();
-                    current_token = Some(Selector::Tag(String::from(char)));
+    ///
+    /// Reports a problem caused by the stylesheet ending early
+    ///
+    fn unexpected_end(&mut self, message: impl Into<String>, offset: usize) {
+        if !self.truncated {
+            self.errors.push(CssError::new(message, offset));
+        }
+    }
 
-                    continue;
-                }
-            }
+    fn parse_tokens(&mut self, source: &str, tokens: &[Token]) {
+        let mut index = 0;
 
-            if char == '(' {
-                in_braces += 1;
-            }
+        while let Some(token) = tokens.get(index) {
+            index = match &token.kind {
+                TokenKind::Whitespace | TokenKind::Cdo | TokenKind::Cdc => index + 1,
+                TokenKind::CloseCurly => {
+                    self.errors.push(CssError::new(
+                        "Unexpected closing brace \"}\"",
+                        token.span.start,
+                    ));
 
-            if char == ')' {
-                in_braces -= 1;
-            }
+                    index + 1
+                }
+                TokenKind::Comment => {
+                    let comment = token.text(source).to_string();
+                    self.stylesheet.blocks.push(StyleBlock::Comment(comment));
 
-            if SELECTOR_END.contains(char) && in_braces == 0 {
-                if let Some(current_token) = current_token.take() {
-                    self.current_selector
-                        .push(Combinator::Combine(current_token))
+                    index + 1
                 }
+                TokenKind::AtKeyword(name) => self.parse_at_rule(source, tokens, index, name),
+                _ => self.parse_rule(source, tokens, index),
+            };
+        }
+    }
 
-                break;
+    ///
+    /// Parses a selector and its block, returning the index of the token following them
+    ///
+    fn parse_rule(&mut self, source: &str, tokens: &[Token], start: usize) -> usize {
+        let selector_start = tokens[start].span.start;
+        let end = find_top_level(tokens, start, |kind| {
+            matches!(kind, TokenKind::OpenCurly | TokenKind::CloseCurly)
+        });
+        let open = match end {
+            Some(open) if tokens[open].kind == TokenKind::OpenCurly => open,
+            Some(close) => {
+                self.errors.push(CssError::new(
+                    "Selector is missing its block!",
+                    selector_start,
+                ));
+
+                return close;
             }
+            None => {
+                self.unexpected_end("Selector is missing its block!", selector_start);
 
-            if char == '+' && in_braces == 0 {
-                if let Some(current_token) = current_token.take() {
-                    self.current_selector
-                        .push(Combinator::Sibling(current_token))
-                }
-
-                break;
+                return tokens.len();
             }
+        };
+        let Some(close) = block_end(tokens, open) else {
+            self.unexpected_end(
+                "Block is missing its closing brace!",
+                tokens[open].span.start,
+            );
 
-            if char == '~' && in_braces == 0 {
-                if let Some(current_token) = current_token.take() {
-                    self.current_selector
-                        .push(Combinator::General(current_token))
-                }
+            return tokens.len();
+        };
 
-                break;
-            }
+        let block =
+            self.parse_normal_block(source, &tokens[start..open], &tokens[open..=close], false);
+        self.stylesheet.blocks.push(StyleBlock::Normal(block));
 
-            if char == '|' && in_braces == 0 {
-                if let Some(current_token) = current_token.take() {
-                    self.current_selector
-                        .push(Combinator::Namespace(current_token))
-                }
+        close + 1
+    }
 
-                break;
-            }
+    ///
+    /// Parses the selectors and the block (including its braces) of a rule. The selectors of
+    /// `nested` rules can start with a combinator.
+    ///
+    fn parse_normal_block(
+        &mut self,
+        source: &str,
+        prelude: &[Token],
+        block: &[Token],
+        nested: bool,
+    ) -> NormalBlock {
+        NormalBlock {
+            selectors: self.parse_selector_list(source, prelude, nested),
+            items: self.parse_block_items(source, &block[1..block.len() - 1]),
+        }
+    }
 
-            if char == '>' && in_braces == 0 {
-                if let Some(current_token) = current_token.take() {
-                    self.current_selector.push(Combinator::Child(current_token))
-                }
+    ///
+    /// Splits the content of a block into its declarations and nested rules. Like in the CSS
+    /// syntax, a statement is a nested rule when a block follows it before any `;`.
+    ///
+    fn parse_block_items(&mut self, source: &str, tokens: &[Token]) -> Vec<BlockItem> {
+        let mut items = Vec::new();
+        let mut declarations_start = 0;
+        let mut index = 0;
+
+        while let Some(token) = tokens.get(index) {
+            if token.is_whitespace() || token.kind == TokenKind::Semicolon {
+                index += 1;
 
-                break;
+                continue;
             }
 
-            if char == ' ' && in_braces == 0 {
-                if let Some(current_token) = current_token.take() {
-                    self.current_selector
-                        .push(Combinator::Descendant(current_token))
-                }
+            let end = find_top_level(tokens, index, |kind| {
+                matches!(kind, TokenKind::Semicolon | TokenKind::OpenCurly)
+            });
 
-                break;
-            }
+            index = match end {
+                Some(open) if tokens[open].kind == TokenKind::OpenCurly => {
+                    // The block is balanced, as it is part of a balanced block itself
+                    let close = block_end(tokens, open).unwrap_or(tokens.len() - 1);
 
-            if char == ']'
-                && current_token
-                    .as_ref()
-                    .map(|token| token.is_attribute())
-                    .unwrap_or_default()
-            {
-                char_iter.next();
+                    self.push_declarations(source, &tokens[declarations_start..index], &mut items);
 
-                continue;
-            }
+                    let item = match &token.kind {
+                        TokenKind::AtKeyword(name) if has_selectors(name) => BlockItem::AtRule {
+                            at_rule: tokens_to_css(source, &tokens[index..open]),
+                            items: self.parse_block_items(source, &tokens[open + 1..close]),
+                        },
+                        TokenKind::AtKeyword(_) => {
+                            BlockItem::Declarations(tokens_to_css(source, &tokens[index..=close]))
+                        }
+                        _ => BlockItem::Rule(self.parse_normal_block(
+                            source,
+                            &tokens[index..open],
+                            &tokens[open..=close],
+                            true,
+                        )),
+                    };
+                    items.push(item);
+                    declarations_start = close + 1;
 
-            if let Some(current_token) = &mut current_token {
-                current_token.push(char);
-                char_iter.next();
-            }
+                    close + 1
+                }
+                Some(semicolon) => semicolon + 1,
+                None => tokens.len(),
+            };
         }
-    }
 
-    pub fn parse_at_rule(&mut self, char_iter: &mut Peekable<Chars>) {
-        let mut raw_rule = String::new();
-        let mut in_block = 0;
-        let rule_name = char_iter
-            .clone()
-            .take_while(|char| char.is_alphanumeric() || "-@_".contains(*char))
-            .collect::<String>();
+        self.push_declarations(source, &tokens[declarations_start..], &mut items);
 
-        if ["@media", "@layer", "@supports", "@container"].contains(&&*rule_name) {
-            self.parse_at_rule_with_selectors(char_iter);
+        items
+    }
 
+    fn push_declarations(&mut self, source: &str, tokens: &[Token], items: &mut Vec<BlockItem>) {
+        if tokens.is_empty() {
             return;
         }
 
+        #[cfg(feature = "css-block-lint")]
+        self.errors.extend(check_css_block_syntax(source, tokens));
+
+        items.push(BlockItem::Declarations(tokens_to_css(source, tokens)));
+    }
+
+    ///
+    /// Parses comma separated selectors, which can start with a combinator when they are `relative`
+    ///
+    fn parse_selector_list(
+        &mut self,
+        source: &str,
+        tokens: &[Token],
+        relative: bool,
+    ) -> Vec<RelativeSelector> {
+        let mut selectors = Vec::new();
+        let mut start = 0;
+
+        if trim_whitespace(tokens).is_empty() {
+            return selectors;
+        }
+
         loop {
-            let Some(char) = char_iter.peek().copied() else {
-                break;
+            let comma = find_top_level(tokens, start, |kind| *kind == TokenKind::Comma);
+            let end = comma.unwrap_or(tokens.len());
+
+            let mut selector = trim_whitespace(&tokens[start..end]);
+            let combinator = match selector.first().map(|token| &token.kind) {
+                Some(TokenKind::Delim(combinator @ ('>' | '+' | '~'))) if relative => {
+                    selector = &selector[1..];
+
+                    Some(*combinator)
+                }
+                _ => None,
             };
 
-            if char == '{' {
-                in_block += 1;
+            if selector.is_empty() {
+                let offset = tokens
+                    .get(end)
+                    .or(tokens.get(start))
+                    .map(|token| token.span.start)
+                    .unwrap_or_default();
+                self.errors.push(CssError::new(
+                    "Selector list contains an empty selector!",
+                    offset,
+                ));
+            } else {
+                selectors.push(RelativeSelector {
+                    combinator,
+                    selector: self.parse_selector(source, selector),
+                });
             }
 
-            if char == '}' {
-                in_block -= 1;
+            match comma {
+                Some(comma) => start = comma + 1,
+                None => return selectors,
+            }
+        }
+    }
 
-                if in_block == 0 {
-                    raw_rule.push(char);
-                    self.stylesheet
-                        .blocks
-                        .push(StyleBlock::GenericAtRule(raw_rule));
-                    char_iter.next();
+    ///
+    /// Parses a complex selector. The last simple selector is always followed by a descendant
+    /// combinator, which separates it from the block.
+    ///
+    fn parse_selector(&mut self, source: &str, tokens: &[Token]) -> ComplexSelector {
+        let tokens = trim_whitespace(tokens);
+        let mut selector = Vec::new();
+        let mut index = 0;
+
+        while index < tokens.len() {
+            let (simple_selector, next) = match self.parse_simple_selector(source, tokens, index) {
+                Ok(parsed) => parsed,
+                Err(next) => {
+                    // Skips the unexpected tokens, along with the whitespace following them
+                    index = next;
+
+                    while tokens.get(index).is_some_and(Token::is_whitespace) {
+                        index += 1;
+                    }
 
-                    break;
+                    continue;
                 }
-            }
+            };
+            index = next;
 
-            if char == ';' && in_block == 0 {
-                raw_rule.push(char);
-                self.stylesheet
-                    .blocks
-                    .push(StyleBlock::GenericAtRule(raw_rule));
-                char_iter.next();
+            let whitespace_start = index;
 
-                break;
+            while tokens.get(index).is_some_and(Token::is_whitespace) {
+                index += 1;
             }
 
-            raw_rule.push(char);
-            char_iter.next();
+            let combinator_start = tokens.get(index).map(|token| token.span.start);
+            // The `||` column combinator is made of two tokens, `|` stands for it
+            let combinator = match tokens.get(index).map(|token| &token.kind) {
+                Some(TokenKind::Delim(combinator @ ('>' | '+' | '~'))) => Some(*combinator),
+                Some(TokenKind::Delim('|'))
+                    if tokens
+                        .get(index + 1)
+                        .is_some_and(|token| token.kind == TokenKind::Delim('|')) =>
+                {
+                    index += 1;
+
+                    Some('|')
+                }
+                _ => None,
+            };
+
+            selector.push(match combinator {
+                Some('>') => Combinator::Child(simple_selector),
+                Some('+') => Combinator::Sibling(simple_selector),
+                Some('~') => Combinator::General(simple_selector),
+                Some(_) => Combinator::Column(simple_selector),
+                None if index > whitespace_start || index == tokens.len() => {
+                    Combinator::Descendant(simple_selector)
+                }
+                None => Combinator::Combine(simple_selector),
+            });
+
+            if let Some(combinator) = combinator {
+                index += 1;
+
+                while tokens.get(index).is_some_and(Token::is_whitespace) {
+                    index += 1;
+                }
+
+                if index == tokens.len() {
+                    let combinator = match combinator {
+                        '|' => "||".to_string(),
+                        combinator => combinator.to_string(),
+                    };
+
+                    self.errors.push(CssError::new(
+                        format!("Combinator \"{combinator}\" is missing its selector!"),
+                        combinator_start.unwrap_or_default(),
+                    ));
+                }
+            }
         }
+
+        selector
     }
 
-    pub fn parse_at_rule_with_selectors(&mut self, char_iter: &mut Peekable<Chars>) {
-        let mut at_rule = String::new();
-        let mut block_content = String::new();
-        let mut in_block = 0;
+    ///
+    /// Parses the simple selector starting at the token at `index`, returning it together with the
+    /// index of the token following it. Invalid input is reported, returning the index to continue
+    /// parsing at.
+    ///
+    fn parse_simple_selector(
+        &mut self,
+        source: &str,
+        tokens: &[Token],
+        index: usize,
+    ) -> Result<(Selector, usize), usize> {
+        let token = &tokens[index];
+        let text = token.text(source);
+        let next = tokens.get(index + 1);
+
+        match token.kind {
+            TokenKind::Ident | TokenKind::Delim('*') | TokenKind::Delim('|') => {
+                let is_name_after = |separator: usize| {
+                    let name = tokens.get(separator + 1);
+
+                    tokens[separator].kind == TokenKind::Delim('|')
+                        && name.is_some_and(|name| {
+                            matches!(name.kind, TokenKind::Ident | TokenKind::Delim('*'))
+                                && name.span.start == tokens[separator].span.end
+                        })
+                };
 
-        loop {
-            let Some(char) = char_iter.peek().copied() else {
-                break;
-            };
+                // A namespace prefix, like `svg|rect`, `*|a` or `|a`. Other `|` are part of the
+                // `||` column combinator.
+                let (namespace, name) = match token.kind {
+                    TokenKind::Delim('|') if is_name_after(index) => {
+                        (Some(String::new()), index + 1)
+                    }
+                    TokenKind::Delim('|') => (None, index),
+                    _ if next.is_some_and(|next| next.span.start == token.span.end)
+                        && is_name_after(index + 1) =>
+                    {
+                        (Some(text.to_string()), index + 2)
+                    }
+                    _ => (None, index),
+                };
 
-            if char == '{' {
-                in_block += 1;
-                char_iter.next();
+                if namespace.is_some() || token.kind != TokenKind::Delim('|') {
+                    let selector = match &tokens[name].kind {
+                        TokenKind::Delim('*') => Selector::Universal(namespace),
+                        _ => Selector::Tag(namespace, tokens[name].text(source).to_string()),
+                    };
 
-                if in_block > 1 {
-                    block_content.push(char);
+                    return Ok((selector, name + 1));
+                }
+            }
+            TokenKind::Delim('&') => return Ok((Selector::Nesting, index + 1)),
+            TokenKind::Hash => return Ok((Selector::Id(text[1..].to_string()), index + 1)),
+            TokenKind::Delim('.') => {
+                if let Some(class) = next.filter(|next| next.kind == TokenKind::Ident) {
+                    return Ok((Selector::Class(class.text(source).to_string()), index + 2));
                 }
+            }
+            TokenKind::OpenSquare => {
+                let close = block_end(tokens, index).unwrap_or(tokens.len());
+                let content = &tokens[index + 1..close];
 
-                continue;
+                if let Some(attribute) = parse_attribute(source, content) {
+                    return Ok((Selector::Attribute(attribute), close + 1));
+                }
+
+                self.errors.push(CssError::new(
+                    format!(
+                        "Attribute selector \"[{}]\" is invalid!",
+                        tokens_to_css(source, trim_whitespace(content))
+                    ),
+                    token.span.start,
+                ));
+
+                return Err(close + 1);
             }
+            TokenKind::Colon => {
+                // Pseudo elements are written with the right amount of colons when compiling
+                let index = match next.map(|next| &next.kind) {
+                    Some(TokenKind::Colon) => index + 2,
+                    _ => index + 1,
+                };
+
+                match tokens.get(index) {
+                    Some(name) if name.kind == TokenKind::Ident => {
+                        let pseudo = name.text(source).to_string();
+
+                        return Ok((Selector::Pseudo(pseudo), index + 1));
+                    }
+                    Some(function) if function.kind == TokenKind::Function => {
+                        let close = block_end(tokens, index).unwrap_or(tokens.len());
+                        let arguments = &tokens[index + 1..close];
+                        let name = function.text(source).trim_end_matches('(');
+
+                        if PSEUDO_CLASSES_WITH_SELECTORS
+                            .iter()
+                            .any(|pseudo| pseudo.eq_ignore_ascii_case(name))
+                        {
+                            let relative = name.eq_ignore_ascii_case("has");
+                            let arguments = self.parse_selector_list(source, arguments, relative);
+                            let pseudo = Selector::FunctionalPseudo(name.to_string(), arguments);
+
+                            return Ok((pseudo, close + 1));
+                        }
 
-            if char == '}' {
-                in_block -= 1;
-
-                if in_block == 0 {
-                    let mut parser = StylesheetParser::default();
-                    parser.parse_stylesheet(block_content);
-
-                    let blocks = parser
-                        .stylesheet
-                        .blocks
-                        .into_iter()
-                        .filter(|block| block.is_normal())
-                        .map(|block| {
-                            match block {
-                                StyleBlock::Normal(normal) => Some(normal),
-                                _ => None,
-                            }
-                            .unwrap()
-                        })
-                        .collect::<Vec<_>>();
-                    let at_rule = AtRuleWithSelectors { at_rule, blocks };
+                        let pseudo = format!("{name}({})", selector_to_css(source, arguments));
 
-                    self.stylesheet
-                        .blocks
-                        .push(StyleBlock::AtRuleWithSelectors(at_rule));
-                    char_iter.next();
+                        return Ok((Selector::Pseudo(pseudo), close + 1));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
 
-                    break;
+        self.errors.push(CssError::new(
+            format!("Unexpected \"{text}\" in the selector!"),
+            token.span.start,
+        ));
+
+        Err(index + 1)
+    }
+
+    ///
+    /// Parses the at-rule starting at the token at `start`, returning the index of the token
+    /// following it
+    ///
+    fn parse_at_rule(&mut self, source: &str, tokens: &[Token], start: usize, name: &str) -> usize {
+        let rule_start = tokens[start].span.start;
+        let with_selectors = has_selectors(name);
+        let end = find_top_level(tokens, start, |kind| {
+            matches!(
+                kind,
+                TokenKind::Semicolon | TokenKind::OpenCurly | TokenKind::CloseCurly
+            )
+        });
+
+        let close = match end.map(|end| (end, &tokens[end].kind)) {
+            Some((end, TokenKind::Semicolon)) => end,
+            Some((open, TokenKind::OpenCurly)) => match block_end(tokens, open) {
+                Some(close) if with_selectors => {
+                    self.parse_at_rule_with_selectors(
+                        source,
+                        &tokens[start..open],
+                        &tokens[open + 1..close],
+                    );
+
+                    return close + 1;
+                }
+                Some(close) => close,
+                None if with_selectors => {
+                    let at_rule = tokens_to_css(source, trim_whitespace(&tokens[start..open]));
+                    self.unexpected_end(
+                        format!("At-rule \"{at_rule}\" is missing its closing brace!"),
+                        rule_start,
+                    );
+
+                    return tokens.len();
+                }
+                None => {
+                    self.unexpected_end(
+                        format!("At-rule \"{name}\" is not terminated!"),
+                        rule_start,
+                    );
+
+                    return tokens.len();
                 }
+            },
+            Some((close, _)) => {
+                self.errors.push(CssError::new(
+                    format!("At-rule \"{name}\" is not terminated!"),
+                    rule_start,
+                ));
+
+                return close;
+            }
+            None => {
+                self.unexpected_end(format!("At-rule \"{name}\" is not terminated!"), rule_start);
+
+                return tokens.len();
+            }
+        };
+
+        let rule = tokens_to_css(source, &tokens[start..=close]);
+
+        if name.eq_ignore_ascii_case("@namespace") {
+            // Only `@charset` and `@import` rules may precede it
+            let first = self.stylesheet.blocks.iter().all(|block| match block {
+                StyleBlock::Namespace(_) | StyleBlock::Comment(_) => true,
+                StyleBlock::GenericAtRule(rule) => leading_rank(rule).is_some(),
+                _ => false,
+            });
+
+            if !first {
+                self.errors.push(CssError::new(
+                    "@namespace has to precede all rules!",
+                    rule_start,
+                ));
             }
 
-            if in_block == 0 {
-                at_rule.push(char);
-                char_iter.next();
+            let default = parse_namespace_rule(&rule).is_some_and(|(prefix, _)| prefix.is_empty());
 
-                continue;
+            if default && !self.global {
+                self.errors.push(CssError::new(
+                    "@namespace without a prefix applies to all styles, declare it in global_style! \
+                    instead!",
+                    rule_start,
+                ));
             }
 
-            block_content.push(char);
-            char_iter.next();
+            self.stylesheet.blocks.push(StyleBlock::Namespace(rule));
+        } else {
+            self.stylesheet.blocks.push(StyleBlock::GenericAtRule(rule));
         }
+
+        close + 1
+    }
+
+    fn parse_at_rule_with_selectors(&mut self, source: &str, prelude: &[Token], block: &[Token]) {
+        let mut parser = StylesheetParser {
+            truncated: self.truncated,
+            ..Default::default()
+        };
+        parser.parse_tokens(source, block);
+        self.errors.append(&mut parser.errors);
+
+        let at_rule = AtRuleWithSelectors {
+            at_rule: tokens_to_css(source, prelude),
+            blocks: parser.stylesheet.blocks,
+        };
+
+        self.stylesheet
+            .blocks
+            .push(StyleBlock::AtRuleWithSelectors(at_rule));
     }
 }
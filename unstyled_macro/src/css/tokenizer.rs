@@ -0,0 +1,610 @@
+//!
+//! Splits a stylesheet into tokens as defined by [CSS Syntax Level 3](https://www.w3.org/TR/css-syntax-3/#tokenization).
+//!
+//! Tokens only know their kind and where they are in the source, the parser writes the source of
+//! the tokens it keeps as is. This way strings, escapes and values are never rewritten.
+//!
+//! Comments are dropped, except for license comments (`/*! ... */`) between rules, when they are
+//! kept.
+//!
+
+use std::ops::Range;
+
+use super::CssError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    Ident,
+    Function,
+    /// An at-keyword like `@media`, with the (unescaped) name including the `@`
+    AtKeyword(String),
+    Hash,
+    String,
+    BadString,
+    Url,
+    BadUrl,
+    Delim(char),
+    Number,
+    Percentage,
+    Dimension,
+    Whitespace,
+    /// `<!--`
+    Cdo,
+    /// `-->`
+    Cdc,
+    Colon,
+    Semicolon,
+    Comma,
+    OpenSquare,
+    CloseSquare,
+    OpenParen,
+    CloseParen,
+    OpenCurly,
+    CloseCurly,
+    /// A license comment (`/*! ... */`) between rules
+    Comment,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    /// The byte range of the token in the source
+    pub span: Range<usize>,
+}
+
+impl Token {
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.span.clone()]
+    }
+
+    pub fn is_whitespace(&self) -> bool {
+        self.kind == TokenKind::Whitespace
+    }
+
+    /// Whether the token opens a block, which is closed by a `)`, `]` or `}`
+    pub fn opens_block(&self) -> bool {
+        matches!(
+            self.kind,
+            TokenKind::Function
+                | TokenKind::OpenParen
+                | TokenKind::OpenSquare
+                | TokenKind::OpenCurly
+        )
+    }
+
+    pub fn closes_block(&self) -> bool {
+        matches!(
+            self.kind,
+            TokenKind::CloseParen | TokenKind::CloseSquare | TokenKind::CloseCurly
+        )
+    }
+
+    /// Whether the token ends with a name or a digit, which a following word would be read with
+    fn ends_word(&self) -> bool {
+        matches!(
+            self.kind,
+            TokenKind::Ident
+                | TokenKind::AtKeyword(_)
+                | TokenKind::Hash
+                | TokenKind::Number
+                | TokenKind::Dimension
+        )
+    }
+
+    fn starts_word(&self) -> bool {
+        matches!(
+            self.kind,
+            TokenKind::Ident
+                | TokenKind::Function
+                | TokenKind::Url
+                | TokenKind::BadUrl
+                | TokenKind::Number
+                | TokenKind::Percentage
+                | TokenKind::Dimension
+        )
+    }
+}
+
+///
+/// The tokens of a stylesheet and the problems found while splitting it up
+///
+pub(crate) struct Tokens {
+    pub tokens: Vec<Token>,
+    pub errors: Vec<CssError>,
+    /// Whether the source ended inside of a string, comment or url, which swallowed the rest of it
+    pub truncated: bool,
+}
+
+pub(crate) fn tokenize(source: &str, keep_license_comments: bool) -> Tokens {
+    let mut tokenizer = Tokenizer {
+        source,
+        position: 0,
+        errors: Vec::new(),
+        truncated: false,
+        keep_license_comments,
+        skipped_comment: false,
+    };
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut depth = 0_usize;
+
+    while let Some(token) = tokenizer.next_token() {
+        if token.kind == TokenKind::Comment {
+            let between_rules = depth == 0
+                && tokens
+                    .iter()
+                    .rfind(|token| !token.is_whitespace())
+                    .is_none_or(|token| {
+                        matches!(token.kind, TokenKind::Semicolon | TokenKind::CloseCurly)
+                    });
+
+            if !between_rules {
+                tokenizer.skipped_comment = true;
+
+                continue;
+            }
+        }
+
+        if token.opens_block() {
+            depth += 1;
+        } else if token.closes_block() {
+            depth = depth.saturating_sub(1);
+        }
+
+        let separated = std::mem::take(&mut tokenizer.skipped_comment);
+
+        match tokens.last_mut() {
+            // The whitespace around a comment is a single whitespace
+            Some(last) if last.is_whitespace() && token.is_whitespace() => {
+                last.span.end = token.span.end;
+
+                continue;
+            }
+            // A comment separates tokens like whitespace, e.g. in `1px/**/solid`
+            Some(last) if separated && last.ends_word() && token.starts_word() => {
+                tokens.push(Token {
+                    kind: TokenKind::Whitespace,
+                    span: token.span.start..token.span.start,
+                });
+            }
+            _ => {}
+        }
+
+        tokens.push(token);
+    }
+
+    Tokens {
+        tokens,
+        errors: tokenizer.errors,
+        truncated: tokenizer.truncated,
+    }
+}
+
+struct Tokenizer<'a> {
+    source: &'a str,
+    /// The byte offset of the next character
+    position: usize,
+    errors: Vec<CssError>,
+    truncated: bool,
+    keep_license_comments: bool,
+    /// Whether a comment was dropped since the last token
+    skipped_comment: bool,
+}
+
+impl Tokenizer<'_> {
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.source[self.position..].chars().nth(n)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_nth(0)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let char = self.peek()?;
+        self.position += char.len_utf8();
+
+        Some(char)
+    }
+
+    fn starts_with(&self, pattern: &str) -> bool {
+        self.source[self.position..].starts_with(pattern)
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        self.consume_comments();
+
+        let start = self.position;
+        let char = self.peek()?;
+        let kind = match char {
+            '/' if self.starts_with("/*!") => {
+                self.consume_comment();
+
+                TokenKind::Comment
+            }
+            char if is_whitespace(char) => {
+                while self.peek().is_some_and(is_whitespace) {
+                    self.bump();
+                }
+
+                TokenKind::Whitespace
+            }
+            '"' | '\'' => self.consume_string(char),
+            '#' if is_ident_char(self.peek_nth(1))
+                || is_valid_escape(self.peek_nth(1), self.peek_nth(2)) =>
+            {
+                self.bump();
+                self.consume_ident_sequence();
+
+                TokenKind::Hash
+            }
+            '+' | '.' if self.starts_number() => self.consume_numeric(),
+            '-' if self.starts_number() => self.consume_numeric(),
+            '-' if self.starts_with("-->") => {
+                self.position += 3;
+
+                TokenKind::Cdc
+            }
+            '-' if self.starts_ident() => self.consume_ident_like(),
+            '<' if self.starts_with("<!--") => {
+                self.position += 4;
+
+                TokenKind::Cdo
+            }
+            '@' if would_start_ident(self.peek_nth(1), self.peek_nth(2), self.peek_nth(3)) => {
+                self.bump();
+
+                TokenKind::AtKeyword(format!("@{}", self.consume_ident_sequence()))
+            }
+            '\\' if self.starts_ident() => self.consume_ident_like(),
+            char if char.is_ascii_digit() => self.consume_numeric(),
+            char if is_ident_start(Some(char)) => self.consume_ident_like(),
+            char => {
+                self.bump();
+
+                match char {
+                    '(' => TokenKind::OpenParen,
+                    ')' => TokenKind::CloseParen,
+                    '[' => TokenKind::OpenSquare,
+                    ']' => TokenKind::CloseSquare,
+                    '{' => TokenKind::OpenCurly,
+                    '}' => TokenKind::CloseCurly,
+                    ',' => TokenKind::Comma,
+                    ':' => TokenKind::Colon,
+                    ';' => TokenKind::Semicolon,
+                    char => TokenKind::Delim(char),
+                }
+            }
+        };
+
+        Some(Token {
+            kind,
+            span: start..self.position,
+        })
+    }
+
+    ///
+    /// Drops the comments at the current position, except for license comments when they are kept
+    ///
+    fn consume_comments(&mut self) {
+        while self.starts_with("/*") && !(self.keep_license_comments && self.starts_with("/*!")) {
+            self.consume_comment();
+            self.skipped_comment = true;
+        }
+    }
+
+    fn consume_comment(&mut self) {
+        let start = self.position;
+
+        match self.source[start + 2..].find("*/") {
+            Some(end) => self.position = start + 2 + end + 2,
+            None => {
+                self.errors.push(CssError::new(
+                    "Comment is missing its closing \"*/\"!",
+                    start,
+                ));
+                self.truncated = true;
+                self.position = self.source.len();
+            }
+        }
+    }
+
+    fn starts_number(&self) -> bool {
+        let (first, second, third) = (self.peek(), self.peek_nth(1), self.peek_nth(2));
+
+        match first {
+            Some('+' | '-') => is_digit(second) || (second == Some('.') && is_digit(third)),
+            Some('.') => is_digit(second),
+            first => is_digit(first),
+        }
+    }
+
+    fn starts_ident(&self) -> bool {
+        would_start_ident(self.peek(), self.peek_nth(1), self.peek_nth(2))
+    }
+
+    fn consume_digits(&mut self) {
+        while is_digit(self.peek()) {
+            self.bump();
+        }
+    }
+
+    fn consume_number(&mut self) {
+        if matches!(self.peek(), Some('+' | '-')) {
+            self.bump();
+        }
+
+        self.consume_digits();
+
+        if self.peek() == Some('.') && is_digit(self.peek_nth(1)) {
+            self.bump();
+            self.consume_digits();
+        }
+
+        let exponent = match (self.peek(), self.peek_nth(1), self.peek_nth(2)) {
+            (Some('e' | 'E'), Some('+' | '-'), digit) => is_digit(digit),
+            (Some('e' | 'E'), digit, _) => is_digit(digit),
+            _ => false,
+        };
+
+        if exponent {
+            self.bump();
+
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.bump();
+            }
+
+            self.consume_digits();
+        }
+    }
+
+    fn consume_numeric(&mut self) -> TokenKind {
+        self.consume_number();
+
+        if self.starts_ident() {
+            self.consume_ident_sequence();
+
+            TokenKind::Dimension
+        } else if self.peek() == Some('%') {
+            self.bump();
+
+            TokenKind::Percentage
+        } else {
+            TokenKind::Number
+        }
+    }
+
+    ///
+    /// Consumes an escape, the `\` already being consumed, and returns the escaped character
+    ///
+    fn consume_escape(&mut self) -> char {
+        match self.bump() {
+            Some(char) if char.is_ascii_hexdigit() => {
+                let mut hex = String::from(char);
+
+                while hex.len() < 6 && self.peek().is_some_and(|char| char.is_ascii_hexdigit()) {
+                    hex.extend(self.bump());
+                }
+
+                if self.peek().is_some_and(is_whitespace) {
+                    self.consume_newline_or_whitespace();
+                }
+
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|value| *value != 0)
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER)
+            }
+            Some(char) => char,
+            None => char::REPLACEMENT_CHARACTER,
+        }
+    }
+
+    /// Consumes a single whitespace, where `\r\n` counts as a single newline
+    fn consume_newline_or_whitespace(&mut self) {
+        if self.bump() == Some('\r') && self.peek() == Some('\n') {
+            self.bump();
+        }
+    }
+
+    fn consume_ident_sequence(&mut self) -> String {
+        let mut value = String::new();
+
+        loop {
+            match self.peek() {
+                Some(char) if is_ident_char(Some(char)) => {
+                    self.bump();
+                    value.push(char);
+                }
+                Some('\\') if is_valid_escape(Some('\\'), self.peek_nth(1)) => {
+                    self.bump();
+                    value.push(self.consume_escape());
+                }
+                _ => return value,
+            }
+        }
+    }
+
+    fn consume_ident_like(&mut self) -> TokenKind {
+        let start = self.position;
+        let name = self.consume_ident_sequence();
+
+        if self.peek() != Some('(') {
+            return TokenKind::Ident;
+        }
+
+        self.bump();
+
+        if !name.eq_ignore_ascii_case("url") {
+            return TokenKind::Function;
+        }
+
+        while self.peek().is_some_and(is_whitespace) && self.peek_nth(1).is_some_and(is_whitespace)
+        {
+            self.bump();
+        }
+
+        // A quoted url is a regular function, holding a string
+        match (self.peek(), self.peek_nth(1)) {
+            (Some('"' | '\''), _) => TokenKind::Function,
+            (Some(whitespace), Some('"' | '\'')) if is_whitespace(whitespace) => {
+                TokenKind::Function
+            }
+            _ => self.consume_url(start),
+        }
+    }
+
+    fn consume_string(&mut self, quote: char) -> TokenKind {
+        let start = self.position;
+        self.bump();
+
+        loop {
+            match self.peek() {
+                None => {
+                    self.errors
+                        .push(CssError::new("Quote is missing its closing quote!", start));
+                    self.truncated = true;
+
+                    return TokenKind::String;
+                }
+                Some(char) if char == quote => {
+                    self.bump();
+
+                    return TokenKind::String;
+                }
+                // The newline is not part of the string, so the rest of the stylesheet is intact
+                Some(char) if is_newline(char) => {
+                    self.errors
+                        .push(CssError::new("Quote is missing its closing quote!", start));
+
+                    return TokenKind::BadString;
+                }
+                Some('\\') => {
+                    self.bump();
+
+                    match self.peek() {
+                        None => {}
+                        Some(char) if is_newline(char) => self.consume_newline_or_whitespace(),
+                        Some(_) => {
+                            self.consume_escape();
+                        }
+                    }
+                }
+                Some(_) => {
+                    self.bump();
+                }
+            }
+        }
+    }
+
+    ///
+    /// Consumes an unquoted url, the `url(` already being consumed
+    ///
+    fn consume_url(&mut self, start: usize) -> TokenKind {
+        while self.peek().is_some_and(is_whitespace) {
+            self.bump();
+        }
+
+        loop {
+            match self.bump() {
+                Some(')') => return TokenKind::Url,
+                None => return self.unterminated_url(start),
+                Some(char) if is_whitespace(char) => {
+                    while self.peek().is_some_and(is_whitespace) {
+                        self.bump();
+                    }
+
+                    return match self.peek() {
+                        Some(')') => {
+                            self.bump();
+
+                            TokenKind::Url
+                        }
+                        None => self.unterminated_url(start),
+                        Some(_) => self.consume_bad_url(start),
+                    };
+                }
+                Some('"' | '\'' | '(') => return self.consume_bad_url(start),
+                Some(char) if is_non_printable(char) => return self.consume_bad_url(start),
+                Some('\\') if is_valid_escape(Some('\\'), self.peek()) => {
+                    self.consume_escape();
+                }
+                Some('\\') => return self.consume_bad_url(start),
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn unterminated_url(&mut self, start: usize) -> TokenKind {
+        self.errors.push(CssError::new(
+            "Url is missing its closing parenthesis!",
+            start,
+        ));
+        self.truncated = true;
+
+        TokenKind::Url
+    }
+
+    ///
+    /// Consumes the rest of an invalid url, up to its closing parenthesis
+    ///
+    fn consume_bad_url(&mut self, start: usize) -> TokenKind {
+        self.errors.push(CssError::new(
+            "Url is invalid, urls containing quotes, parentheses or whitespace have to be quoted!",
+            start,
+        ));
+
+        loop {
+            match self.bump() {
+                Some(')') => return TokenKind::BadUrl,
+                None => {
+                    self.truncated = true;
+
+                    return TokenKind::BadUrl;
+                }
+                Some('\\') if is_valid_escape(Some('\\'), self.peek()) => {
+                    self.consume_escape();
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+fn is_whitespace(char: char) -> bool {
+    matches!(char, ' ' | '\t') || is_newline(char)
+}
+
+fn is_newline(char: char) -> bool {
+    matches!(char, '\n' | '\r' | '\x0C')
+}
+
+fn is_non_printable(char: char) -> bool {
+    matches!(char, '\0'..='\x08' | '\x0B' | '\x0E'..='\x1F' | '\x7F')
+}
+
+fn is_digit(char: Option<char>) -> bool {
+    char.is_some_and(|char| char.is_ascii_digit())
+}
+
+fn is_ident_start(char: Option<char>) -> bool {
+    char.is_some_and(|char| char.is_ascii_alphabetic() || char == '_' || !char.is_ascii())
+}
+
+fn is_ident_char(char: Option<char>) -> bool {
+    is_ident_start(char) || is_digit(char) || char == Some('-')
+}
+
+fn is_valid_escape(first: Option<char>, second: Option<char>) -> bool {
+    first == Some('\\') && second.is_some_and(|char| !is_newline(char))
+}
+
+fn would_start_ident(first: Option<char>, second: Option<char>, third: Option<char>) -> bool {
+    match first {
+        Some('-') => {
+            is_ident_start(second) || second == Some('-') || is_valid_escape(second, third)
+        }
+        Some('\\') => is_valid_escape(first, second),
+        first => is_ident_start(first),
+    }
+}
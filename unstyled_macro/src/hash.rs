@@ -0,0 +1,72 @@
+//!
+//! The scope class of a style is derived from a hash of its css. SSR and CSR builds, possibly made
+//! with different compiler versions, have to agree on these classes, so the hash has to be fixed
+//! instead of relying on the (explicitly unspecified) `DefaultHasher`.
+//!
+//! The scope class is `un-` followed by the 64 bit [FNV-1a](http://www.isthe.com/chongo/tech/comp/fnv/)
+//! hash of the (optional) salt followed by the style, encoded in base36 (`0-9a-z`) and cut down
+//! to its last `length` digits.
+//! **The same css always results in the same class, this must not change between releases.**
+//!
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+const BASE36_DIGITS: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// The default amount of base36 digits in a scope class
+pub(crate) const DEFAULT_LENGTH: usize = 8;
+/// The smallest amount of base36 digits a scope class can be configured to
+pub(crate) const MIN_LENGTH: usize = 4;
+/// The largest amount of base36 digits a scope class can have, as `36^13 > u64::MAX`
+pub(crate) const MAX_LENGTH: usize = 13;
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_continue(FNV_OFFSET_BASIS, bytes)
+}
+
+fn fnv1a_continue(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+///
+/// Encodes the last `length` base36 digits of the value, padded with zeros
+///
+pub(crate) fn base36(mut value: u64, length: usize) -> String {
+    let mut digits = vec![b'0'; length];
+
+    for digit in digits.iter_mut().rev() {
+        *digit = BASE36_DIGITS[(value % 36) as usize];
+        value /= 36;
+    }
+
+    String::from_utf8(digits).expect("Base36 digits are ascii")
+}
+
+fn scope_hash(salt: &str, style: &str, length: usize) -> String {
+    base36(
+        fnv1a_continue(fnv1a(salt.as_bytes()), style.as_bytes()),
+        length,
+    )
+}
+
+pub(crate) fn scope_class(salt: &str, style: &str, length: usize) -> String {
+    format!("un-{}", scope_hash(salt, style, length))
+}
+
+///
+/// A scope class, which also contains a name to tie it back to its component, like `un-Card-3fk9a`.
+/// Characters, which are not allowed in a class name, are replaced with a `-`.
+///
+pub(crate) fn debug_scope_class(name: &str, salt: &str, style: &str, length: usize) -> String {
+    let name = name
+        .chars()
+        .map(|char| match char {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => char,
+            _ => '-',
+        })
+        .collect::<String>();
+
+    format!("un-{name}-{}", scope_hash(salt, style, length))
+}
@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+
+use proc_macro2::{Delimiter, LineColumn, Span, TokenStream, TokenTree};
+use syn::parse::{Parse, ParseStream};
+use syn::{Ident, Lit, LitStr, Token, Visibility};
+
+const ARGUMENTS: &[&str] = &["name"];
+
+///
+/// Defines a `const` holding the style instead of evaluating to it, e.g. `pub CARD = "..."`
+///
+pub(crate) struct ConstItem {
+    pub visibility: Visibility,
+    pub ident: Ident,
+}
+
+///
+/// The css of a style, either as a (raw) string literal or written directly as tokens
+///
+pub(crate) enum Css {
+    Literal(LitStr),
+    /// The css reconstructed from the tokens, with the offset each token starts at in it
+    Tokens {
+        css: String,
+        spans: Vec<(usize, Span)>,
+    },
+    /// The css read from a file, see `style_file!`
+    File {
+        path: LitStr,
+        file: PathBuf,
+        css: String,
+    },
+}
+
+impl Css {
+    pub fn value(&self) -> String {
+        match self {
+            Css::Literal(literal) => literal.value(),
+            Css::Tokens { css, .. } | Css::File { css, .. } => css.clone(),
+        }
+    }
+
+    ///
+    /// Rebuilds the css from its tokens. Rust splits a lot of css into multiple tokens, like
+    /// `#fff`, `-webkit-box`, `50%` or `::before`, so tokens are joined directly when they are
+    /// adjacent in the source and separated by a single space otherwise.
+    ///
+    fn from_tokens(tokens: TokenStream) -> Self {
+        let mut css = String::new();
+        let mut spans = Vec::new();
+        let mut previous_end = None;
+
+        push_tokens(tokens, &mut css, &mut spans, &mut previous_end);
+
+        Css::Tokens { css, spans }
+    }
+}
+
+fn push_tokens(
+    tokens: TokenStream,
+    css: &mut String,
+    spans: &mut Vec<(usize, Span)>,
+    previous_end: &mut Option<LineColumn>,
+) {
+    for token in tokens {
+        let (text, span) = match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::None => {
+                        push_tokens(group.stream(), css, spans, previous_end);
+                        continue;
+                    }
+                };
+
+                push_token(open, group.span_open(), css, spans, previous_end);
+                push_tokens(group.stream(), css, spans, previous_end);
+
+                (close.to_string(), group.span_close())
+            }
+            TokenTree::Ident(ident) => (ident.to_string(), ident.span()),
+            TokenTree::Punct(punct) => (punct.as_char().to_string(), punct.span()),
+            TokenTree::Literal(literal) => (literal.to_string(), literal.span()),
+        };
+
+        push_token(&text, span, css, spans, previous_end);
+    }
+}
+
+fn push_token(
+    text: &str,
+    span: Span,
+    css: &mut String,
+    spans: &mut Vec<(usize, Span)>,
+    previous_end: &mut Option<LineColumn>,
+) {
+    if previous_end.is_some_and(|end| end != span.start()) {
+        css.push(' ');
+    }
+
+    spans.push((css.len(), span));
+    css.push_str(text);
+    *previous_end = Some(span.end());
+}
+
+///
+/// The input of the `style!` macro: optional `key = "value",` arguments, an optional const item
+/// and the css, either as a (raw) string literal or as tokens
+///
+pub(crate) struct StyleInput {
+    /// Used for readable scope classes in debug builds, e.g. `name = "Card"`
+    pub name: Option<LitStr>,
+    pub item: Option<ConstItem>,
+    pub css: Css,
+}
+
+impl Parse for StyleInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let (name, item) = parse_header(input)?;
+        let css = input.parse::<Css>()?;
+
+        Ok(Self { name, item, css })
+    }
+}
+
+impl Parse for Css {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // Css written as tokens never starts with a literal
+        if input.peek(Lit) {
+            Ok(Css::Literal(parse_literal(input, "css")?))
+        } else {
+            Ok(Css::from_tokens(input.parse::<TokenStream>()?))
+        }
+    }
+}
+
+///
+/// The input of the `style_file!` macro, which takes the same arguments and const item as
+/// `style!`, followed by the path of the css file
+///
+pub(crate) struct StyleFileInput {
+    pub name: Option<LitStr>,
+    pub item: Option<ConstItem>,
+    pub path: LitStr,
+}
+
+impl Parse for StyleFileInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let (name, item) = parse_header(input)?;
+        let path = parse_literal(input, "path of the css file")?;
+
+        Ok(Self { name, item, path })
+    }
+}
+
+///
+/// Parses the optional `key = "value",` arguments and the optional const item
+///
+fn parse_header(input: ParseStream) -> syn::Result<(Option<LitStr>, Option<ConstItem>)> {
+    let mut name = None;
+
+    while input.peek(Ident) && input.peek2(Token![=]) {
+        let key = input.fork().parse::<Ident>()?;
+
+        if !ARGUMENTS.contains(&&*key.to_string()) {
+            break;
+        }
+
+        input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+
+        if key == "name" {
+            name = Some(input.parse::<LitStr>()?);
+        }
+
+        input.parse::<Token![,]>()?;
+    }
+
+    let item = if input.peek(Token![pub]) || (input.peek(Ident) && input.peek2(Token![=])) {
+        let visibility = input.parse::<Visibility>()?;
+        let ident = input.parse::<Ident>()?;
+        input.parse::<Token![=]>()?;
+
+        Some(ConstItem { visibility, ident })
+    } else {
+        None
+    };
+
+    Ok((name, item))
+}
+
+///
+/// Parses the string literal ending the input
+///
+fn parse_literal(input: ParseStream, what: &str) -> syn::Result<LitStr> {
+    let literal = input.parse::<LitStr>().map_err(|error| {
+        syn::Error::new(
+            error.span(),
+            format!("Expected the {what} as a string literal"),
+        )
+    })?;
+
+    if !input.is_empty() {
+        return Err(input.error(format!("Unexpected input after the {what} string literal")));
+    }
+
+    Ok(literal)
+}
@@ -1,66 +1,408 @@
-use proc_macro::TokenStream;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use quote::quote;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use crate::css::StylesheetParser;
+use crate::config::Config;
+use crate::css::{CssError, StylesheetParser};
+use crate::input::{ConstItem, Css, StyleFileInput, StyleInput};
+use crate::store::{Fragment, Origin};
 
+mod config;
 mod css;
+mod hash;
+mod input;
+mod store;
 
-// TODO: GET RID OF THIS!!
-static mut GENERATED_STYLES: Option<HashMap<String, String>> = None;
-
 ///
-/// Generates a style class for the components and emits the style
+/// Generates a style class for the components and emits the style, evaluating to an `unstyled::Style`.
+///
+/// The css is either a string literal or written directly as tokens, like `style! { .a { color: red; } }`.
+/// Starting the input with `pub CARD = ` defines a `const` holding the style instead.
 ///
 #[cfg_attr(not(test), proc_macro)]
 pub fn style(tokens: TokenStream) -> TokenStream {
-    unsafe {
-        if GENERATED_STYLES.is_none() {
-            GENERATED_STYLES.replace(HashMap::new());
+    let input = match syn::parse::<StyleInput>(tokens) {
+        Ok(input) => input,
+        Err(error) => return error.into_compile_error().into(),
+    };
+
+    style_item(input)
+}
+
+///
+/// Generates a style class for the components from the css in a file and emits the style, just
+/// like `style!`. The path is relative to the calling source file, or else to the crate's
+/// `Cargo.toml`. Changes to the file trigger a rebuild.
+///
+#[cfg_attr(not(test), proc_macro)]
+pub fn style_file(tokens: TokenStream) -> TokenStream {
+    let StyleFileInput { name, item, path } = match syn::parse::<StyleFileInput>(tokens) {
+        Ok(input) => input,
+        Err(error) => return error.into_compile_error().into(),
+    };
+    let file = resolve_file(&path.value());
+    let css = match std::fs::read_to_string(&file) {
+        Ok(css) => css,
+        Err(error) => {
+            let message = format!("Could not read {}: {error}", file.display());
+            return syn::Error::new(path.span(), message)
+                .into_compile_error()
+                .into();
         }
-    }
+    };
 
-    let style = tokens.to_string();
-    let mut hasher = DefaultHasher::new();
-    style.hash(&mut hasher);
-    let scope_class = format!("un-{}", hasher.finish());
-    let scope_class_lit = format!(r#"{{ unstyled::write_style!(); "{scope_class}"}}"#);
+    style_item(StyleInput {
+        name,
+        item,
+        css: Css::File { path, file, css },
+    })
+}
+
+///
+/// Emits unscoped css, like resets, `:root` variables or `@font-face` rules. It is written to the
+/// merged stylesheet as is, before all scoped styles, but checked for errors just like `style!`.
+///
+#[cfg_attr(not(test), proc_macro)]
+pub fn global_style(tokens: TokenStream) -> TokenStream {
+    let input = match syn::parse::<Css>(tokens) {
+        Ok(input) => input,
+        Err(error) => return error.into_compile_error().into(),
+    };
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(message) => return compile_error(&message, Span::call_site()),
+    };
+    let style = input.value();
     let mut parser = StylesheetParser::default();
-    parser.parse_stylesheet(style);
+    parser.global = true;
+    parser.parse_stylesheet(style.clone());
+
+    if !parser.errors.is_empty() {
+        return compile_errors(&style, &input, &parser.errors);
+    }
+
+    let css = style.trim().to_string();
+    let digest = hash::fnv1a(css.as_bytes());
+    let name = format!("global-{}", hash::base36(digest, hash::MAX_LENGTH));
+    let fragment = Fragment {
+        global: true,
+        origins: vec![origin()],
+        digest,
+        css,
+    };
+
+    if let Err(message) = store::write_fragment(&config, &name, fragment) {
+        return compile_error(&message, Span::call_site());
+    }
+
+    TokenStream::from_str("unstyled::write_style!();").expect("Can return the style")
+}
 
-    let style = parser.stylesheet.compile(&scope_class);
+///
+/// Evaluates to the style, or defines a const holding it
+///
+fn style_item(input: StyleInput) -> TokenStream {
+    let style = proc_macro2::TokenStream::from(style_expression(&input));
 
-    unsafe {
-        if GENERATED_STYLES.is_none() {
-            GENERATED_STYLES = Some(HashMap::new());
+    match input.item {
+        None => style.into(),
+        Some(ConstItem { visibility, ident }) => {
+            quote!(#visibility const #ident: unstyled::Style = #style;).into()
         }
+    }
+}
+
+///
+/// Resolves the path of a css file like `include_str!` does, falling back to the crate's
+/// directory when there is no such file next to the calling source file
+///
+fn resolve_file(path: &str) -> PathBuf {
+    let caller_dir = Span::call_site()
+        .local_file()
+        .and_then(|file| file.parent().map(Path::to_path_buf));
+    let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    let mut candidates = caller_dir
+        .into_iter()
+        .chain(manifest_dir)
+        .map(|dir| dir.join(path));
+    let first = candidates.next().unwrap_or_else(|| PathBuf::from(path));
+
+    std::iter::once(first.clone())
+        .chain(candidates)
+        .find(|file| file.is_file())
+        .unwrap_or(first)
+}
+
+///
+/// Compiles the style and persists it, returning the expression evaluating to the `unstyled::Style`
+///
+fn style_expression(input: &StyleInput) -> TokenStream {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(message) => return with_errors(compile_error(&message, Span::call_site()), ""),
+    };
+    let style = input.css.value();
+    let mut parser = StylesheetParser::default();
+    parser.keep_license_comments = config.keep_license_comments;
+    parser.parse_stylesheet(style.clone());
+
+    let normalized = parser.stylesheet.to_string();
+    let scope_class = if config.debug_names {
+        let name = match (&input.name, &input.item) {
+            (Some(name), _) => name.value(),
+            (None, Some(item)) => item.ident.to_string(),
+            (None, None) => match &input.css {
+                Css::File { file, .. } => module_name(&file.to_string_lossy()),
+                _ => module_name(&Span::call_site().file()),
+            },
+        };
+
+        hash::debug_scope_class(&name, &config.salt, &normalized, config.hash_length)
+    } else {
+        hash::scope_class(&config.salt, &normalized, config.hash_length)
+    };
+
+    if !parser.errors.is_empty() {
+        return with_errors(
+            compile_errors(&style, &input.css, &parser.errors),
+            &scope_class,
+        );
+    }
+
+    if config.flatten_nesting {
+        parser.stylesheet.flatten_nesting();
+    }
+
+    let css = parser.stylesheet.compile(&scope_class);
+    let fragment = Fragment {
+        global: false,
+        origins: vec![origin()],
+        digest: hash::fnv1a(normalized.as_bytes()),
+        css: css.clone(),
+    };
+
+    if let Err(message) = store::write_fragment(&config, &scope_class, fragment) {
+        return with_errors(compile_error(&message, Span::call_site()), &scope_class);
+    }
+
+    let style = style_value(&scope_class, &css);
+    // Makes cargo rebuild the crate when the css file changes
+    let dependency = match &input.css {
+        Css::File { file, .. } => {
+            let file = std::fs::canonicalize(file).unwrap_or_else(|_| file.clone());
+            let file = Literal::string(&file.to_string_lossy());
 
-        if let Some(styles) = &mut GENERATED_STYLES {
-            styles.insert(scope_class, style);
+            format!("const _: &[u8] = include_bytes!({file});")
         }
+        _ => String::new(),
+    };
+
+    TokenStream::from_str(&format!(
+        "{{ {dependency} unstyled::write_style!(); {style} }}"
+    ))
+    .expect("Can return the style")
+}
+
+///
+/// The `unstyled::Style` expression the macro evaluates to
+///
+fn style_value(scope_class: &str, css: &str) -> TokenStream {
+    TokenStream::from_str(&format!(
+        "unstyled::Style::new({}, {})",
+        Literal::string(scope_class),
+        Literal::string(css)
+    ))
+    .expect("Can return the style")
+}
+
+///
+/// Names styles without an explicit name after the file they are defined in, or its directory for
+/// `mod.rs` files
+///
+fn module_name(file: &str) -> String {
+    let path = std::path::Path::new(file);
+    let name = match path.file_stem() {
+        Some(stem) if stem == "mod" => path.parent().and_then(|parent| parent.file_name()),
+        stem => stem,
     };
 
-    TokenStream::from_str(&scope_class_lit).expect("Can return scope_class")
+    name.map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
 }
 
 ///
-/// This macro is used to write the generated and buffered css styles into a single file
+/// The location of the macro invocation currently being expanded
+///
+fn origin() -> Origin {
+    let span = Span::call_site();
+
+    Origin {
+        crate_name: std::env::var("CARGO_CRATE_NAME").unwrap_or_default(),
+        bin: std::env::var_os("CARGO_BIN_NAME").is_some(),
+        file: span.file(),
+        line: span.line(),
+        column: span.column(),
+        source: span
+            .local_file()
+            .and_then(|file| Some(std::env::current_dir().ok()?.join(file)))
+            .unwrap_or_default(),
+    }
+}
+
+///
+/// Wraps the `compile_error!`s in a block, which still evaluates to a style, so the errors are not
+/// followed by type mismatches
+///
+fn with_errors(mut errors: TokenStream, scope_class: &str) -> TokenStream {
+    errors.extend(style_value(scope_class, ""));
+
+    TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, errors)))
+}
+
+///
+/// Turns the errors of a stylesheet into `compile_error!` invocations
+///
+fn compile_errors(css: &str, input: &Css, errors: &[CssError]) -> TokenStream {
+    match input {
+        Css::Literal(literal) => literal_errors(css, literal, errors),
+        Css::Tokens { spans, .. } => token_errors(spans, errors),
+        Css::File { path, file, .. } => file_errors(css, path, file, errors),
+    }
+}
+
+///
+/// Points each error at the token the problem starts at
+///
+fn token_errors(spans: &[(usize, proc_macro2::Span)], errors: &[CssError]) -> TokenStream {
+    errors
+        .iter()
+        .flat_map(|error| {
+            let span = spans
+                .iter()
+                .rev()
+                .find(|(offset, _)| *offset <= error.offset)
+                .map(|(_, span)| span.unwrap())
+                .unwrap_or_else(Span::call_site);
+
+            compile_error(&error.message, span)
+        })
+        .collect()
+}
+
+///
+/// Points the errors at the style literal, naming the line and column of each problem inside of it.
+///
+/// The offsets of the errors point into the unescaped css, which matches the source of the literal
+/// as long as it contains no escapes.
+///
+fn literal_errors(css: &str, literal: &syn::LitStr, errors: &[CssError]) -> TokenStream {
+    let span = literal.span().unwrap();
+    // The quote, prefixed by the `r#` of raw strings
+    let prefix = literal
+        .token()
+        .to_string()
+        .find('"')
+        .map(|quote| quote + 1)
+        .unwrap_or_default();
+    let mut output = TokenStream::new();
+
+    for error in errors {
+        let (line, column) = position(css, error.offset);
+        // Span::line and Span::column are both one-indexed
+        let (line, column) = match line {
+            0 => (span.line(), span.column() + prefix + column),
+            line => (span.line() + line, column + 1),
+        };
+        let message = format!("{} (at line {line}, column {column})", error.message);
+
+        output.extend(compile_error(&message, span));
+    }
+
+    output
+}
+
+///
+/// Points the errors at the path literal, naming the file, line and column of each problem
+///
+fn file_errors(css: &str, path: &syn::LitStr, file: &Path, errors: &[CssError]) -> TokenStream {
+    let span = path.span().unwrap();
+
+    errors
+        .iter()
+        .flat_map(|error| {
+            let (line, column) = position(css, error.offset);
+            let message = format!(
+                "{} (at {}:{}:{})",
+                error.message,
+                file.display(),
+                line + 1,
+                column + 1
+            );
+
+            compile_error(&message, span)
+        })
+        .collect()
+}
+
+///
+/// The zero-indexed line and column (in characters) of an offset into the css
+///
+fn position(css: &str, offset: usize) -> (usize, usize) {
+    let before = &css[..offset.min(css.len())];
+    let line = before.matches('\n').count();
+    let column = before
+        .chars()
+        .rev()
+        .take_while(|char| *char != '\n')
+        .count();
+
+    (line, column)
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+
+    let mut arguments = Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from(TokenTree::Literal(literal)),
+    );
+    arguments.set_span(span);
+
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+
+    let mut semicolon = Punct::new(';', Spacing::Alone);
+    semicolon.set_span(span);
+
+    TokenStream::from_iter([
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(arguments),
+        TokenTree::Punct(semicolon),
+    ])
+}
+
+///
+/// This macro is used to merge the written style fragments into a single file
 ///
 #[doc(hidden)]
 #[cfg_attr(not(test), proc_macro)]
 pub fn write_style(_: TokenStream) -> TokenStream {
-    let target_dir = std::env::current_dir().unwrap().join("target");
-    unsafe {
-        if let Some(styles) = &GENERATED_STYLES {
-            let styles = styles.values().cloned().collect::<Vec<_>>().join("\n");
+    let written = Config::load().and_then(|config| {
+        store::write_stylesheet(&config).map_err(|error| {
+            format!(
+                "Could not write the merged stylesheet to {}: {error}",
+                config.output.display()
+            )
+        })
+    });
 
-            std::fs::write(target_dir.join("unstyled.css"), styles).unwrap();
-        }
+    match written {
+        Ok(()) => TokenStream::new(),
+        Err(message) => compile_error(&message, Span::call_site()),
     }
-
-    TokenStream::new()
 }
 
 #[cfg(test)]
@@ -103,7 +445,10 @@ mod test {
         let mut parser = StylesheetParser::default();
         parser.parse_stylesheet(css);
         let compiled = parser.stylesheet.compile("random_test_class");
-        assert_eq!(compiled, ".random_test_class:not(.test) {display: block;}");
+        assert_eq!(
+            compiled,
+            ".random_test_class:not(.test.random_test_class) {display: block;}"
+        );
     }
 
     #[test]
@@ -152,15 +497,172 @@ mod test {
     }
 
     #[test]
-    pub fn test_combined_namespace() {
+    pub fn test_namespace_prefix() {
+        let css = "svg|rect, *|a, |b, svg|* {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "svg|rect.random_test_class,*|a.random_test_class,|b.random_test_class,svg|*.random_test_class {display: block;}"
+        );
+    }
+
+    #[test]
+    pub fn test_universal_selector() {
+        let css =
+            "* { box-sizing: border-box; } * > .x, .a *, *.b, *:hover, :not(*) {}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "*.random_test_class { box-sizing: border-box; }*.random_test_class>.x.random_test_class,.a.random_test_class *.random_test_class,*.b.random_test_class,*.random_test_class:hover,.random_test_class:not(*.random_test_class) {}"
+        );
+    }
+
+    #[test]
+    pub fn test_universal_selector_with_namespace() {
+        let css = "*|*, svg|*, |* > a {}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "*|*.random_test_class,svg|*.random_test_class,|*.random_test_class>a.random_test_class {}"
+        );
+        assert_eq!(parser.stylesheet.to_string(), "*|*,svg|*,|*>a {}");
+    }
+
+    #[test]
+    pub fn test_column_combinator() {
+        let css = "col.selected || td, col||.cell {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "col.selected.random_test_class||td.random_test_class,col.random_test_class||.cell.random_test_class {display: block;}"
+        );
+    }
+
+    #[test]
+    pub fn test_error_single_bar_combinator() {
         let css = ".test-class | .sub-class {display: block;}".to_string();
         let mut parser = StylesheetParser::default();
         parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 12);
+    }
+
+    #[test]
+    pub fn test_error_trailing_combinator() {
+        let css = ".a > { color: red; } .c + , .d {} .e ~ {} .f || {}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let offsets = parser
+            .errors
+            .iter()
+            .map(|error| error.offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![3, 24, 37, 45]);
+        assert_eq!(
+            parser.errors[0].message,
+            "Combinator \">\" is missing its selector!"
+        );
+        assert_eq!(
+            parser.errors[3].message,
+            "Combinator \"||\" is missing its selector!"
+        );
+    }
+
+    #[test]
+    pub fn test_namespace_rule() {
+        let css = "@namespace svg url(http://www.w3.org/2000/svg);\nsvg|a {}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "@namespace svg url(http://www.w3.org/2000/svg);svg|a.random_test_class {}"
+        );
+    }
+
+    #[test]
+    pub fn test_error_namespace_rule_after_rules() {
+        let css = "a {}\n@namespace svg url(http://www.w3.org/2000/svg);".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 5);
+    }
+
+    #[test]
+    pub fn test_namespace_rule_after_imports() {
+        let css =
+            "@charset \"utf-8\"; @import url(a.css); /* svg */ @namespace svg url(b); svg|a {}"
+                .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
         let compiled = parser.stylesheet.compile("random_test_class");
         assert_eq!(
             compiled,
-            ".test-class.random_test_class|.sub-class.random_test_class {display: block;}"
+            "@charset \"utf-8\";@import url(a.css);@namespace svg url(b);svg|a.random_test_class {}"
+        );
+    }
+
+    #[test]
+    pub fn test_error_default_namespace_in_scoped_style() {
+        let css = "@namespace url(http://www.w3.org/2000/svg); rect {}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css.clone());
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 0);
+
+        let mut global_parser = StylesheetParser::default();
+        global_parser.global = true;
+        global_parser.parse_stylesheet(css);
+        assert!(global_parser.errors.is_empty());
+    }
+
+    #[test]
+    pub fn test_parse_namespace_rule() {
+        let parse = crate::css::parse_namespace_rule;
+        assert_eq!(
+            parse("@namespace svg url( b );"),
+            Some(("svg".to_string(), "b".to_string()))
+        );
+        assert_eq!(
+            parse("@namespace url(\"b\");"),
+            Some((String::new(), "b".to_string()))
+        );
+        assert_eq!(
+            parse("@namespace svg \"b\";"),
+            Some(("svg".to_string(), "b".to_string()))
+        );
+        assert_eq!(parse("@import url(b);"), None);
+    }
+
+    #[test]
+    pub fn test_split_leading_rules() {
+        let (rules, css) = crate::css::split_leading_rules(
+            "/*! license */@namespace svg url(a);@namespace url(b); svg|a.un-1 {}",
         );
+        assert_eq!(rules, ["@namespace svg url(a);", "@namespace url(b);"]);
+        assert_eq!(css, "/*! license */ svg|a.un-1 {}");
+        let (rules, css) =
+            crate::css::split_leading_rules("@import url(a.css); @NAMESPACE url(b); .a {}");
+        assert_eq!(rules, ["@import url(a.css);", "@NAMESPACE url(b);"]);
+        assert_eq!(css, "  .a {}");
+        let (rules, css) = crate::css::split_leading_rules(".a {} @namespace url(b);");
+        assert!(rules.is_empty());
+        assert_eq!(css, ".a {} @namespace url(b);");
     }
 
     #[test]
@@ -187,6 +689,44 @@ mod test {
         );
     }
 
+    #[test]
+    pub fn test_selector_list() {
+        let css = ".a, .b > .c,\n:deep(.d) , span:is(.e, .f) {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".a.random_test_class,.b.random_test_class>.c.random_test_class,.d,span.random_test_class:is(.e.random_test_class,.f.random_test_class) {display: block;}"
+        );
+        assert_eq!(
+            parser.stylesheet.to_string(),
+            ".a,.b>.c,:deep(.d),span:is(.e,.f) {display: block;}"
+        );
+    }
+
+    #[test]
+    pub fn test_selector_list_in_at_rule() {
+        let css = "@media print { h1, h2 { margin: 0; } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "@media print {h1.random_test_class,h2.random_test_class { margin: 0; }}"
+        );
+    }
+
+    #[test]
+    pub fn test_error_empty_selector_in_list() {
+        let css = ".a, , .b {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 4);
+    }
+
     #[test]
     pub fn test_at_keyframes() {
         let css =
@@ -231,95 +771,1138 @@ mod test {
     }
 
     #[test]
-    pub fn test_at_layer() {
-        let css = "@layer my_fancy_layer { .my-element { margin: 1210000000em; } }".to_string();
+    pub fn test_nested_at_rules() {
+        let css = "@supports (display: grid) { @media print { .a { x: y; } } } @layer base { @media (width > 1px) { @container (width > 2px) { .b { x: y; } } } .c { x: y; } }"
+            .to_string();
         let mut parser = StylesheetParser::default();
         parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
         let compiled = parser.stylesheet.compile("random_test_class");
         assert_eq!(
             compiled,
-            "@layer my_fancy_layer {.my-element.random_test_class { margin: 1210000000em; }}"
+            "@supports (display: grid) {@media print {.a.random_test_class { x: y; }}}@layer base {@media (width > 1px) {@container (width > 2px) {.b.random_test_class { x: y; }}}.c.random_test_class { x: y; }}"
+        );
+        assert_eq!(
+            parser.stylesheet.to_string(),
+            "@supports (display: grid) {@media print {.a {x: y;}}}@layer base {@media (width > 1px) {@container (width > 2px) {.b {x: y;}}}.c {x: y;}}"
         );
     }
 
     #[test]
-    pub fn test_pseudo_element() {
-        let css = "span::before { content: '$'; display: block; }".to_string();
+    pub fn test_nested_at_rules_keep_other_rules() {
+        let css =
+            "@media print { @keyframes a { to { x: y; } } @layer b; .c { x: y; } }".to_string();
         let mut parser = StylesheetParser::default();
         parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
         let compiled = parser.stylesheet.compile("random_test_class");
         assert_eq!(
             compiled,
-            "span.random_test_class::before { content: '$'; display: block; }"
+            "@media print {@keyframes a { to { x: y; } }@layer b;.c.random_test_class { x: y; }}"
         );
     }
 
-    /// Unstyled ensures, that pseudo elements (like the `::before`) actually have the double colon
-    /// (::) as defined by the [CSS Pseudo-Elements Module Level 4](https://drafts.csswg.org/css-pseudo/)
     #[test]
-    pub fn test_pseudo_element_corrected() {
-        let css = "span:before { content: '$'; display: block; }".to_string();
+    pub fn test_nested_at_rules_flattened() {
+        let css = "@layer a { @media print { .b { &:hover { x: y; } } } }".to_string();
         let mut parser = StylesheetParser::default();
         parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
         let compiled = parser.stylesheet.compile("random_test_class");
         assert_eq!(
             compiled,
-            "span.random_test_class::before { content: '$'; display: block; }"
+            "@layer a {@media print {.b.random_test_class:hover { x: y; }}}"
         );
     }
 
     #[test]
-    pub fn test_pseudo_selector_deep() {
-        let css = "span :deep(*:not(a)) { content: '$'; display: block; }".to_string();
+    pub fn test_error_in_nested_at_rule() {
+        let css = "@media print { @supports (x: y) { .a, { x: y; } } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 37);
+    }
+
+    #[test]
+    pub fn test_at_layer() {
+        let css = "@layer my_fancy_layer { .my-element { margin: 1210000000em; } }".to_string();
         let mut parser = StylesheetParser::default();
         parser.parse_stylesheet(css);
         let compiled = parser.stylesheet.compile("random_test_class");
         assert_eq!(
             compiled,
-            "span.random_test_class *:not(a) { content: '$'; display: block; }"
+            "@layer my_fancy_layer {.my-element.random_test_class { margin: 1210000000em; }}"
         );
     }
 
-    #[cfg(feature = "css-block-lint")]
-    mod lint_tests {
-        use crate::css::StylesheetParser;
-
-        #[test]
-        #[should_panic]
-        pub fn test_lint_empty_value_panic() {
+    #[test]
+    pub fn test_pseudo_selector_arguments() {
+        let css = ".a:not(.b .c):where(:is(d, e)) {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".a:not(.b.random_test_class .c.random_test_class).random_test_class:where(.random_test_class:is(d.random_test_class,e.random_test_class)) {display: block;}"
+        );
+    }
+
+    #[test]
+    pub fn test_pseudo_has() {
+        let css = ".card:has(> img, + .b ~ .c) {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".card.random_test_class:has(>img.random_test_class,+.b.random_test_class~.c.random_test_class) {display: block;}"
+        );
+    }
+
+    #[test]
+    pub fn test_pseudo_argument_deep() {
+        let css = "a:not(:deep(.external)) {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "a.random_test_class:not(.external) {display: block;}"
+        );
+    }
+
+    #[test]
+    pub fn test_error_combinator_outside_has() {
+        let css = "a:not(> b) {display: block;}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 6);
+    }
+
+    #[test]
+    pub fn test_pseudo_element() {
+        let css = "span::before { content: '$'; display: block; }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "span.random_test_class::before { content: '$'; display: block; }"
+        );
+    }
+
+    /// Unstyled ensures, that pseudo elements (like the `::before`) actually have the double colon
+    /// (::) as defined by the [CSS Pseudo-Elements Module Level 4](https://drafts.csswg.org/css-pseudo/)
+    #[test]
+    pub fn test_pseudo_element_corrected() {
+        let css = "span:before { content: '$'; display: block; }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "span.random_test_class::before { content: '$'; display: block; }"
+        );
+    }
+
+    #[test]
+    pub fn test_pseudo_selector_deep() {
+        let css = "span :deep(*:not(a)) { content: '$'; display: block; }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "span.random_test_class *:not(a) { content: '$'; display: block; }"
+        );
+    }
+
+    #[test]
+    pub fn test_error_unclosed_block() {
+        let css = ".test {display: block;".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 6);
+    }
+
+    #[test]
+    pub fn test_error_unexpected_closing_brace() {
+        let css = ".test {display: block;}\n}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 24);
+    }
+
+    #[test]
+    pub fn test_error_selector_without_block() {
+        let css = ".test {display: block;}\n  .dangling".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 26);
+    }
+
+    #[test]
+    pub fn test_error_unterminated_at_rule() {
+        let css = "@import 'test'".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 0);
+    }
+
+    #[test]
+    pub fn test_normalized_ignores_formatting() {
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(".test > .sub-class {display: block;}".to_string());
+        let mut formatted_parser = StylesheetParser::default();
+        formatted_parser.parse_stylesheet(
+            "\n    .test>.sub-class {\n        display:   block;\n    }\n\n".to_string(),
+        );
+        assert_eq!(
+            parser.stylesheet.to_string(),
+            ".test>.sub-class {display: block;}"
+        );
+        assert_eq!(
+            formatted_parser.stylesheet.to_string(),
+            parser.stylesheet.to_string()
+        );
+    }
+
+    #[test]
+    pub fn test_values_are_not_rewritten() {
+        let css = r#"a::after { content: "a::b > c"; width: calc(1px + 2px); background: url(data:image/png;base64,iVBO+w==); }"#.to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            r#"a.random_test_class::after { content: "a::b > c"; width: calc(1px + 2px); background: url(data:image/png;base64,iVBO+w==); }"#
+        );
+    }
+
+    #[test]
+    pub fn test_attribute_selectors() {
+        let css = r#"[lang|=en], [data-x="a > b"], [title~="x"], a[href$=".pdf" i], [title="a]b"], [ns|name], [|name], [*|name^=x s] {}"#
+            .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            r#"[lang|=en].random_test_class,[data-x="a > b"].random_test_class,[title~="x"].random_test_class,a[href$=".pdf" i].random_test_class,[title="a]b"].random_test_class,[ns|name].random_test_class,[|name].random_test_class,[*|name^=x s].random_test_class {}"#
+        );
+    }
+
+    #[test]
+    pub fn test_attribute_selector_whitespace() {
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(r#"[ lang |= en ], [ title = "a" i ] {}"#.to_string());
+        assert!(parser.errors.is_empty());
+        assert_eq!(
+            parser.stylesheet.to_string(),
+            r#"[lang|=en],[title="a" i] {}"#
+        );
+    }
+
+    #[test]
+    pub fn test_error_invalid_attribute_selectors() {
+        for (css, offset) in [
+            ("a[b=1] {}", 1),
+            ("[a b] {}", 0),
+            (".c [=a] {}", 3),
+            ("[a~ =b] {}", 0),
+            ("[a=b x] {}", 0),
+        ] {
+            let mut parser = StylesheetParser::default();
+            parser.parse_stylesheet(css.to_string());
+            assert_eq!(parser.errors.len(), 1, "{css}");
+            assert_eq!(parser.errors[0].offset, offset, "{css}");
+        }
+    }
+
+    #[test]
+    pub fn test_value_operators_are_kept() {
+        let css = r#".a > .b { width: calc(100% - 1px + 2em); grid-area: 1 / 2; content: "a ~ b | c::d"; background: url("https://example.com/a::b"); }"#
+            .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            r#".a.random_test_class>.b.random_test_class { width: calc(100% - 1px + 2em); grid-area: 1 / 2; content: "a ~ b | c::d"; background: url("https://example.com/a::b"); }"#
+        );
+        assert_eq!(
+            parser.stylesheet.to_string(),
+            r#".a>.b {width: calc(100% - 1px + 2em);grid-area: 1 / 2;content: "a ~ b | c::d";background: url("https://example.com/a::b");}"#
+        );
+    }
+
+    #[test]
+    pub fn test_value_operators_are_kept_in_at_rules_and_nested_rules() {
+        let css = "@supports (width: calc(1px + 2px)) { .a { width: calc(1px + 2px); & > .b { margin: calc(1px - -2px); } } }"
+            .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            "@supports (width: calc(1px + 2px)) {.a.random_test_class { width: calc(1px + 2px); &>.b.random_test_class { margin: calc(1px - -2px); } }}"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting() {
+        let css =
+            ".card { color: red; &:hover { color: blue; } .title { margin: 0; } > p { x: y; } }"
+                .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".card.random_test_class { color: red; &.random_test_class:hover { color: blue; } .title.random_test_class { margin: 0; } >p.random_test_class { x: y; } }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_flattened() {
+        let css = ".a, .b { color: red; &:hover { color: blue; } .dark & { color: white; } > p { .c { x: y; } } }"
+            .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".a.random_test_class,.b.random_test_class { color: red; }\
+             .a.random_test_class:hover,.b.random_test_class:hover { color: blue; }\
+             .dark.random_test_class .a.random_test_class,.dark.random_test_class .b.random_test_class { color: white; }\
+             .a.random_test_class>p.random_test_class .c.random_test_class,.b.random_test_class>p.random_test_class .c.random_test_class { x: y; }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_keeps_nested_at_rules() {
+        let css = ".a { @media (width > 10px) { color: red; } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".a.random_test_class { @media (width > 10px) { color: red; } }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_flattened_in_pseudo_classes() {
+        let css = ".a { :not(&) { x: y; } .b:is(& .e) { x: y; } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".random_test_class:not(.a.random_test_class) { x: y; }\
+             .b.random_test_class:is(.a.random_test_class .e.random_test_class) { x: y; }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_flattened_into_compound() {
+        let css = ".x .y { h2& { x: y; } .a&:hover { x: y; } } div { h2& { x: y; } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".x.random_test_class h2.y.random_test_class { x: y; }\
+             .x.random_test_class .y.a.random_test_class:hover { x: y; }\
+             h2.random_test_class:is(div.random_test_class) { x: y; }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_flattened_with_multiple_parents() {
+        let css = ".p, .q { & + & { x: y; } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".p.random_test_class+.p.random_test_class,.p.random_test_class+.q.random_test_class,\
+             .q.random_test_class+.p.random_test_class,.q.random_test_class+.q.random_test_class { x: y; }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_scopes_nested_at_rules() {
+        let css = ".c { @media (width > 10px) { .d { x: y; } } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".c.random_test_class { @media (width > 10px) { .d.random_test_class { x: y; } } }"
+        );
+    }
+
+    #[test]
+    pub fn test_nesting_flattened_hoists_nested_at_rules() {
+        let css = ".a, .b { color: red; @media (width > 10px) { color: blue; @supports (x: y) { .d { x: y; } } } }"
+            .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".a.random_test_class,.b.random_test_class { color: red; }\
+             @media (width > 10px) {.a.random_test_class,.b.random_test_class { color: blue; }\
+             @supports (x: y) {.a.random_test_class .d.random_test_class,.b.random_test_class .d.random_test_class { x: y; }}}"
+        );
+    }
+
+    #[test]
+    pub fn test_escaped_class() {
+        let css = r".sm\:flex{display:flex}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(compiled, r".sm\:flex.random_test_class {display:flex}");
+    }
+
+    #[test]
+    pub fn test_escaped_identifiers() {
+        let css =
+            r".w-1\/2, .\31 0, .md\:hover\:bg-\[\#fff\], #\31 23, .\@sm\:p-4:hover, a[data-\:x] {}"
+                .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            r".w-1\/2.random_test_class,.\31 0.random_test_class,.md\:hover\:bg-\[\#fff\].random_test_class,#\31 23.random_test_class,.\@sm\:p-4.random_test_class:hover,a[data-\:x].random_test_class {}"
+        );
+    }
+
+    #[test]
+    pub fn test_dashed_and_non_ascii_identifiers() {
+        let css = ".-a, .--b, .-\\31 c, -moz-x, .grün, .🚀 > #ñ, ._c {}".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".-a.random_test_class,.--b.random_test_class,.-\\31 c.random_test_class,-moz-x.random_test_class,.grün.random_test_class,.🚀.random_test_class>#ñ.random_test_class,._c.random_test_class {}"
+        );
+    }
+
+    #[test]
+    pub fn test_escaped_identifiers_in_nested_and_pseudo_selectors() {
+        let css = r".a { &.sm\:flex, :not(.w-1\/2) { x: y; } }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        parser.stylesheet.flatten_nesting();
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            r".a.sm\:flex.random_test_class,.a.random_test_class .random_test_class:not(.w-1\/2.random_test_class) { x: y; }"
+        );
+    }
+
+    #[test]
+    pub fn test_comments() {
+        let css =
+            "/* a: 'b */ .a /* c */ > .b { color: /* d */ red; /* e: f */ border: 1px/**/solid; }"
+                .to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert!(parser.errors.is_empty());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(
+            compiled,
+            ".a.random_test_class>.b.random_test_class { color: red; border: 1px solid; }"
+        );
+    }
+
+    #[test]
+    pub fn test_license_comments() {
+        let css = "/*! MIT */\n.a { /*! inside */ color: red; }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css.clone());
+        let compiled = parser.stylesheet.compile("random_test_class");
+        assert_eq!(compiled, ".a.random_test_class { color: red; }");
+
+        let mut kept_parser = StylesheetParser::default();
+        kept_parser.keep_license_comments = true;
+        kept_parser.parse_stylesheet(css);
+        let compiled = kept_parser.stylesheet.compile("random_test_class");
+        assert_eq!(compiled, "/*! MIT */.a.random_test_class { color: red; }");
+        assert_eq!(
+            kept_parser.stylesheet.to_string(),
+            parser.stylesheet.to_string()
+        );
+    }
+
+    #[test]
+    pub fn test_error_unterminated_comment() {
+        let css = ".test {display: block; /* }".to_string();
+        let mut parser = StylesheetParser::default();
+        parser.parse_stylesheet(css);
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(parser.errors[0].offset, 23);
+    }
+
+    mod tokenizer_tests {
+        use crate::css::tokenizer::{tokenize, TokenKind};
+
+        fn kinds(css: &str) -> Vec<TokenKind> {
+            tokenize(css, false)
+                .tokens
+                .into_iter()
+                .map(|token| token.kind)
+                .collect()
+        }
+
+        #[test]
+        pub fn test_selector_tokens() {
+            assert_eq!(
+                kinds("#id>a.b[c='d']:not(e)"),
+                vec![
+                    TokenKind::Hash,
+                    TokenKind::Delim('>'),
+                    TokenKind::Ident,
+                    TokenKind::Delim('.'),
+                    TokenKind::Ident,
+                    TokenKind::OpenSquare,
+                    TokenKind::Ident,
+                    TokenKind::Delim('='),
+                    TokenKind::String,
+                    TokenKind::CloseSquare,
+                    TokenKind::Colon,
+                    TokenKind::Function,
+                    TokenKind::Ident,
+                    TokenKind::CloseParen,
+                ]
+            );
+        }
+
+        #[test]
+        pub fn test_numeric_tokens() {
+            assert_eq!(
+                kinds("1 -.5em 50% +2e3 1e-2px"),
+                vec![
+                    TokenKind::Number,
+                    TokenKind::Whitespace,
+                    TokenKind::Dimension,
+                    TokenKind::Whitespace,
+                    TokenKind::Percentage,
+                    TokenKind::Whitespace,
+                    TokenKind::Number,
+                    TokenKind::Whitespace,
+                    TokenKind::Dimension,
+                ]
+            );
+        }
+
+        #[test]
+        pub fn test_other_tokens() {
+            assert_eq!(
+                kinds("<!-- @media url( a.png ) url(\"b\") --> /* comment */ -webkit-box \\31 0"),
+                vec![
+                    TokenKind::Cdo,
+                    TokenKind::Whitespace,
+                    TokenKind::AtKeyword("@media".to_string()),
+                    TokenKind::Whitespace,
+                    TokenKind::Url,
+                    TokenKind::Whitespace,
+                    TokenKind::Function,
+                    TokenKind::String,
+                    TokenKind::CloseParen,
+                    TokenKind::Whitespace,
+                    TokenKind::Cdc,
+                    TokenKind::Whitespace,
+                    TokenKind::Ident,
+                    TokenKind::Whitespace,
+                    TokenKind::Ident,
+                ]
+            );
+        }
+
+        #[test]
+        pub fn test_bad_tokens() {
+            let tokens = tokenize("a { content: 'b\n; } url(d e)", false);
+            let offsets = tokens
+                .errors
+                .iter()
+                .map(|error| error.offset)
+                .collect::<Vec<_>>();
+            assert_eq!(offsets, vec![13, 20]);
+            assert!(!tokens.truncated);
+            assert!(tokens
+                .tokens
+                .iter()
+                .any(|token| token.kind == TokenKind::BadString));
+            assert_eq!(tokens.tokens.last().unwrap().kind, TokenKind::BadUrl);
+        }
+    }
+
+    mod input_tests {
+        use crate::css::StylesheetParser;
+        use crate::input::{StyleFileInput, StyleInput};
+
+        #[test]
+        pub fn test_raw_string() {
+            let input = syn::parse_str::<StyleInput>(r####"r#"a[title="b"] {}"#"####).unwrap();
+            assert_eq!(input.css.value(), r#"a[title="b"] {}"#);
+        }
+
+        #[test]
+        pub fn test_escapes() {
+            let input = syn::parse_str::<StyleInput>(r#""a { content: \"\\\" }""#).unwrap();
+            assert_eq!(input.css.value(), r#"a { content: "\" }"#);
+        }
+
+        #[test]
+        pub fn test_arguments_and_item() {
+            let input =
+                syn::parse_str::<StyleInput>(r#"name = "Card", pub CARD = ".a {}""#).unwrap();
+            assert_eq!(input.name.unwrap().value(), "Card");
+            assert_eq!(input.item.unwrap().ident, "CARD");
+            assert_eq!(input.css.value(), ".a {}");
+        }
+
+        #[test]
+        pub fn test_rejects_other_input() {
+            assert!(syn::parse_str::<StyleInput>("42").is_err());
+            assert!(syn::parse_str::<StyleInput>(r#"".a {}" ".b {}""#).is_err());
+            assert!(syn::parse_str::<StyleInput>(r#"b".a {}""#).is_err());
+        }
+
+        #[test]
+        pub fn test_file() {
+            let input =
+                syn::parse_str::<StyleFileInput>(r#"name = "Card", CARD = "card.css""#).unwrap();
+            assert_eq!(input.name.unwrap().value(), "Card");
+            assert_eq!(input.item.unwrap().ident, "CARD");
+            assert_eq!(input.path.value(), "card.css");
+            assert!(syn::parse_str::<StyleFileInput>(".a {}").is_err());
+            assert!(syn::parse_str::<StyleFileInput>(r#""a.css" "b.css""#).is_err());
+        }
+
+        #[test]
+        pub fn test_tokens_keep_value_operators() {
+            let input = syn::parse_str::<StyleInput>(
+                r#".a { width: calc(100% - 1px + 2px); content: "a > b::c"; }"#,
+            )
+            .unwrap();
+            assert_eq!(
+                input.css.value(),
+                r#".a { width: calc(100% - 1px + 2px); content: "a > b::c"; }"#
+            );
+        }
+
+        #[test]
+        pub fn test_tokens() {
+            let input = syn::parse_str::<StyleInput>(
+                r#"
+                #id > a::before { content: "x"; color: #fff; -webkit-box-flex: 1; }
+                .title:hover, div .a { margin: 1rem 50% calc(100% - 1.5rem); }
+                @media (min-width: 120px) { .a { --custom-value: 0; } }
+                "#,
+            )
+            .unwrap();
+            assert_eq!(
+                input.css.value(),
+                r#"#id > a::before { content: "x"; color: #fff; -webkit-box-flex: 1; } .title:hover, div .a { margin: 1rem 50% calc(100% - 1.5rem); } @media (min-width: 120px) { .a { --custom-value: 0; } }"#
+            );
+        }
+
+        #[test]
+        pub fn test_tokens_with_item() {
+            let input = syn::parse_str::<StyleInput>("pub CARD = .card { color: red; }").unwrap();
+            assert_eq!(input.item.unwrap().ident, "CARD");
+            assert_eq!(input.css.value(), ".card { color: red; }");
+        }
+
+        #[test]
+        pub fn test_tokens_compile_like_literal() {
+            let tokens = syn::parse_str::<StyleInput>(".test>.sub-class { display: block; }")
+                .unwrap()
+                .css
+                .value();
+            let mut parser = StylesheetParser::default();
+            parser.parse_stylesheet(tokens);
+            let compiled = parser.stylesheet.compile("random_test_class");
+            assert_eq!(
+                compiled,
+                ".test.random_test_class>.sub-class.random_test_class { display: block; }"
+            );
+        }
+    }
+
+    mod hash_tests {
+        use crate::css::StylesheetParser;
+        use crate::hash;
+
+        #[test]
+        pub fn test_fnv1a() {
+            assert_eq!(hash::fnv1a(b""), 0xcbf29ce484222325);
+            assert_eq!(hash::fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+            assert_eq!(hash::fnv1a(b"foobar"), 0x85944171f73967e8);
+        }
+
+        #[test]
+        pub fn test_base36() {
+            assert_eq!(hash::base36(0, 4), "0000");
+            assert_eq!(hash::base36(35, 4), "000z");
+            assert_eq!(hash::base36(36 * 36 * 36 * 36 + 37, 4), "0011");
+            assert_eq!(hash::base36(u64::MAX, 13), "3w5e11264sgsf");
+        }
+
+        #[test]
+        pub fn test_scope_class_salt() {
+            let css = ".test {display: block;}";
+            assert_eq!(
+                hash::scope_class("my-salt", css, 8),
+                hash::scope_class("", &format!("my-salt{css}"), 8)
+            );
+            assert_ne!(
+                hash::scope_class("my-salt", css, 8),
+                hash::scope_class("", css, 8)
+            );
+        }
+
+        #[test]
+        pub fn test_debug_scope_class() {
+            let css = ".test {display: block;}";
+            assert_eq!(
+                hash::debug_scope_class("Card", "", css, 8),
+                "un-Card-y5b1ig7x"
+            );
+            assert_eq!(
+                hash::debug_scope_class("Card title!", "", css, 4),
+                "un-Card-title--ig7x"
+            );
+        }
+
+        /// Normalizing a stylesheet must not change either, as it is what the scope class is
+        /// hashed from
+        #[test]
+        pub fn test_normalized_scope_class_is_stable() {
+            let styles = [
+                ".test {display: block;}",
+                "\n  .a > .b, .c:not(.d) {\n    color: red;\n  }\n",
+                "@media (width > 1px) { @supports (x: y) { a[href$=\".pdf\" i] { x: y; } } }",
+                ".card { color: red; &:hover { color: blue; } .title { margin: 0; } }",
+                "svg|rect, *|*, col || td, .sm\\:flex::before { content: \"a > b\"; }",
+            ];
+            let classes = styles.map(|css| {
+                let mut parser = StylesheetParser::default();
+                parser.parse_stylesheet(css.to_string());
+                assert!(parser.errors.is_empty(), "{css}");
+                hash::scope_class("", &parser.stylesheet.to_string(), 8)
+            });
+
+            assert_eq!(
+                classes,
+                [
+                    "un-y5b1ig7x",
+                    "un-icl39guu",
+                    "un-shcqx5x5",
+                    "un-q77t5wqp",
+                    "un-84j144q6"
+                ]
+            );
+        }
+
+        /// The scope classes must never change, as SSR and CSR builds have to agree on them
+        #[test]
+        pub fn test_scope_class_is_stable() {
+            let css = ".test {display: block;}";
+            assert_eq!(hash::scope_class("", css, 8), "un-y5b1ig7x");
+            assert_eq!(hash::scope_class("", css, 4), "un-ig7x");
+            assert_eq!(hash::scope_class("", css, 13), "un-0b0dpy5b1ig7x");
+        }
+    }
+
+    mod config_tests {
+        use crate::config;
+
+        #[test]
+        pub fn test_config_target_dir() {
+            let root = std::env::temp_dir().join(format!("unstyled-config-{}", std::process::id()));
+            let crate_dir = root.join("crates").join("app");
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(root.join(".cargo")).unwrap();
+            std::fs::create_dir_all(crate_dir.join(".cargo")).unwrap();
+            // Configs above the temporary directory are not part of the test
+            let dirs = || {
+                crate_dir
+                    .ancestors()
+                    .take_while(|dir| dir.starts_with(&root))
+            };
+            assert_eq!(config::config_target_dir(dirs()).unwrap(), None);
+
+            std::fs::write(
+                root.join(".cargo").join("config.toml"),
+                "[build]\ntarget-dir = \"out\"",
+            )
+            .unwrap();
+            assert_eq!(
+                config::config_target_dir(dirs()).unwrap(),
+                Some(root.join("out"))
+            );
+
+            // The closest config takes precedence
+            std::fs::write(
+                crate_dir.join(".cargo").join("config"),
+                "[build]\ntarget-dir = \"/absolute\"",
+            )
+            .unwrap();
+            assert_eq!(
+                config::config_target_dir(dirs()).unwrap(),
+                Some("/absolute".into())
+            );
+        }
+    }
+
+    mod store_tests {
+        use std::path::PathBuf;
+
+        use crate::config::Config;
+        use crate::store::{self, Fragment, Origin};
+
+        fn config(name: &str) -> Config {
+            let dir = std::env::temp_dir().join(format!("unstyled-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+
+            Config {
+                fragment_dir: dir.join("unstyled"),
+                output: dir.join("unstyled.css"),
+                hash_length: 8,
+                salt: String::new(),
+                debug_names: false,
+                keep_license_comments: false,
+                flatten_nesting: false,
+            }
+        }
+
+        fn origin(line: usize) -> Origin {
+            Origin {
+                crate_name: "app".to_string(),
+                bin: false,
+                file: "src/main.rs".to_string(),
+                line,
+                column: 0,
+                source: PathBuf::new(),
+            }
+        }
+
+        fn fragment(origin: Origin, css: &str) -> Fragment {
+            Fragment {
+                global: false,
+                origins: vec![origin],
+                digest: crate::hash::fnv1a(css.as_bytes()),
+                css: css.to_string(),
+            }
+        }
+
+        /// Writes a fragment like an earlier build did, which this process doesn't know about
+        fn write_old_fragment(config: &Config, scope_class: &str, fragment: Fragment) {
+            let mut file = format!(
+                "unstyled-fragment-3\t{}\t{:016x}\t{}\n",
+                if fragment.global { "global" } else { "scoped" },
+                fragment.digest,
+                fragment.origins.len()
+            );
+
+            for origin in &fragment.origins {
+                file.push_str(&format!(
+                    "{}\tlib\t{}\t{}\t{}\t{}\n",
+                    origin.crate_name,
+                    origin.file,
+                    origin.line,
+                    origin.column,
+                    origin.source.display()
+                ));
+            }
+
+            std::fs::create_dir_all(&config.fragment_dir).unwrap();
+            std::fs::write(
+                config.fragment_dir.join(format!("{scope_class}.css")),
+                file + &fragment.css,
+            )
+            .unwrap();
+        }
+
+        fn merged(config: &Config) -> String {
+            store::write_stylesheet(config).unwrap();
+            std::fs::read_to_string(&config.output).unwrap()
+        }
+
+        #[test]
+        pub fn test_merge_hoists_leading_rules() {
+            let config = config("leading");
+            let namespace = "@namespace svg url(b);svg|a.un-a {}";
+            let import = "@import url(a.css);@namespace svg url(b);.b {}";
+            store::write_fragment(&config, "un-a", fragment(origin(1), namespace)).unwrap();
+            store::write_fragment(&config, "un-b", fragment(origin(2), import)).unwrap();
+            assert_eq!(
+                merged(&config),
+                "@import url(a.css);\n@namespace svg url(b);\nsvg|a.un-a {}\n.b {}"
+            );
+        }
+
+        #[test]
+        pub fn test_merge_namespaces_by_prefix() {
+            let config = config("namespaces");
+            let a = "@namespace svg url(b);svg|a.un-a {}";
+            let b = "@namespace svg \"b\";svg|b.un-b {}";
+            store::write_fragment(&config, "un-a", fragment(origin(1), a)).unwrap();
+            store::write_fragment(&config, "un-b", fragment(origin(2), b)).unwrap();
+            assert_eq!(
+                merged(&config),
+                "@namespace svg url(b);\nsvg|a.un-a {}\nsvg|b.un-b {}"
+            );
+        }
+
+        #[test]
+        pub fn test_merge_rejects_conflicting_namespaces() {
+            let config = config("conflicting-namespaces");
+            let a = "@namespace svg url(b);svg|a.un-a {}";
+            let c = "@namespace svg url(c);svg|c.un-c {}";
+            store::write_fragment(&config, "un-a", fragment(origin(1), a)).unwrap();
+            store::write_fragment(&config, "un-c", fragment(origin(2), c)).unwrap();
+            let error = store::write_stylesheet(&config).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "The namespace prefix \"svg\" is declared as \"c\" by the style at src/main.rs:2:0 \
+                (crate app), but as \"b\" by the style at src/main.rs:1:0 (crate app)"
+            );
+        }
+
+        #[test]
+        pub fn test_merge_in_order() {
+            let config = config("merge");
+            store::write_fragment(&config, "un-b", fragment(origin(2), ".b {}")).unwrap();
+            store::write_fragment(&config, "un-a", fragment(origin(1), ".a {}")).unwrap();
+            store::write_fragment(&config, "un-b", fragment(origin(0), ".b {}")).unwrap();
+            assert_eq!(merged(&config), ".b {}\n.a {}");
+        }
+
+        #[test]
+        pub fn test_skip_fragments_of_other_formats() {
+            let config = config("format");
+            store::write_fragment(&config, "un-a", fragment(origin(1), ".a {}")).unwrap();
+            let old = config.fragment_dir.join("un-old.css");
+            std::fs::write(
+                &old,
+                "scoped\tapp\tsrc/main.rs\t1\t0\t0000000000000000\n.old {}",
+            )
+            .unwrap();
+            std::fs::write(config.fragment_dir.join("un-broken.css"), [0xff, 0xfe]).unwrap();
+            assert_eq!(merged(&config), ".a {}");
+            assert!(!old.exists());
+        }
+
+        #[test]
+        pub fn test_merge_rereads_fragments_written_by_other_processes() {
+            let config = config("other-processes");
+            store::write_fragment(&config, "un-a", fragment(origin(1), ".a {}")).unwrap();
+            store::write_fragment(&config, "un-b", fragment(origin(2), ".b {}")).unwrap();
+            assert_eq!(merged(&config), ".a {}\n.b {}");
+
+            // Another crate using the same style moves it to the front
+            let mut shared = fragment(origin(2), ".b {}");
+            let mut other = origin(0);
+            other.crate_name = "aaa".to_string();
+            shared.origins.insert(0, other);
+            write_old_fragment(&config, "un-b", shared);
+            assert_eq!(merged(&config), ".b {}\n.a {}");
+        }
+
+        #[test]
+        pub fn test_edited_style_replaces_the_old_one() {
+            let config = config("edited");
+            let other_crate = Origin {
+                crate_name: "other".to_string(),
+                ..origin(1)
+            };
+            let shared = Fragment {
+                origins: vec![origin(5), other_crate],
+                ..fragment(origin(5), ".shared {}")
+            };
+            write_old_fragment(&config, "un-old", fragment(origin(1), ".old {}"));
+            write_old_fragment(&config, "un-shared", shared);
+            store::write_fragment(&config, "un-new", fragment(origin(1), ".new {}")).unwrap();
+            // The other crate wasn't compiled, so its styles are kept
+            assert_eq!(merged(&config), ".new {}\n.shared {}");
+            assert!(!config.fragment_dir.join("un-old.css").exists());
+        }
+
+        #[test]
+        pub fn test_moved_style_merges_like_a_fresh_build() {
+            let incremental = config("moved");
+            write_old_fragment(&incremental, "un-a", fragment(origin(1), ".a {}"));
+            write_old_fragment(&incremental, "un-c", fragment(origin(2), ".c {}"));
+            store::write_fragment(&incremental, "un-c", fragment(origin(2), ".c {}")).unwrap();
+            store::write_fragment(&incremental, "un-a", fragment(origin(3), ".a {}")).unwrap();
+
+            let fresh = config("moved-fresh");
+            store::write_fragment(&fresh, "un-c", fragment(origin(2), ".c {}")).unwrap();
+            store::write_fragment(&fresh, "un-a", fragment(origin(3), ".a {}")).unwrap();
+
+            assert_eq!(merged(&incremental), ".c {}\n.a {}");
+            assert_eq!(merged(&incremental), merged(&fresh));
+        }
+
+        #[test]
+        pub fn test_edited_and_removed_global_styles_are_dropped() {
+            let global = |origin, css| Fragment {
+                global: true,
+                ..fragment(origin, css)
+            };
+            let config = config("global");
+            write_old_fragment(
+                &config,
+                "global-red",
+                global(origin(1), "body { color: red; }"),
+            );
+            write_old_fragment(
+                &config,
+                "global-reset",
+                global(origin(2), "* { margin: 0; }"),
+            );
+            // The style was edited and moved a line down, the reset was removed
+            let blue = global(origin(2), "body { color: blue; }");
+            store::write_fragment(&config, "global-blue", blue).unwrap();
+            store::write_fragment(&config, "un-a", fragment(origin(3), ".a {}")).unwrap();
+            assert_eq!(merged(&config), "body { color: blue; }\n.a {}");
+        }
+
+        #[test]
+        pub fn test_styles_of_deleted_files_are_removed() {
+            let config = config("deleted");
+            let deleted = Origin {
+                source: std::env::temp_dir().join("unstyled-deleted-source.rs"),
+                ..origin(1)
+            };
+            write_old_fragment(&config, "un-deleted", fragment(deleted, ".deleted {}"));
+            store::write_fragment(&config, "un-a", fragment(origin(2), ".a {}")).unwrap();
+            assert_eq!(merged(&config), ".a {}");
+            assert!(!config.fragment_dir.join("un-deleted.css").exists());
+        }
+    }
+
+    #[cfg(feature = "css-block-lint")]
+    mod lint_tests {
+        use crate::css::StylesheetParser;
+
+        #[test]
+        pub fn test_lint_empty_value() {
             let css =
                 "span :deep(*:not(a)) { content: '$'; display: block; --no-value:; }".to_string();
             let mut parser = StylesheetParser::default();
             parser.parse_stylesheet(css);
-            parser.stylesheet.compile("random_test_class");
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(parser.errors[0].offset, 53);
         }
 
         #[test]
-        #[should_panic]
         pub fn test_lint_unclosed_quote() {
             let css = "span :deep(*:not(a)) { content: '$; }".to_string();
             let mut parser = StylesheetParser::default();
             parser.parse_stylesheet(css);
-            parser.stylesheet.compile("random_test_class");
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(parser.errors[0].offset, 32);
         }
 
         #[test]
-        #[should_panic]
         pub fn test_lint_missing_semi() {
             let css = "span :deep(*:not(a)) { content: '$;' display: block; }".to_string();
             let mut parser = StylesheetParser::default();
             parser.parse_stylesheet(css);
-            parser.stylesheet.compile("random_test_class");
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(parser.errors[0].offset, 23);
+        }
+
+        #[test]
+        pub fn test_lint_nested_rules() {
+            let css = ".a { color: red; &:hover { color: blue; } .b { --no-value:; } }".to_string();
+            let mut parser = StylesheetParser::default();
+            parser.parse_stylesheet(css);
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(parser.errors[0].offset, 47);
+        }
+
+        #[test]
+        pub fn test_lint_declarations_after_nested_at_rule() {
+            let css = ".e { @media (x) { color: red; } color: blue; }".to_string();
+            let mut parser = StylesheetParser::default();
+            parser.parse_stylesheet(css);
+            assert!(parser.errors.is_empty());
+            let compiled = parser.stylesheet.compile("random_test_class");
+            assert_eq!(
+                compiled,
+                ".e.random_test_class { @media (x) { color: red; } color: blue; }"
+            );
+        }
+
+        #[test]
+        pub fn test_lint_nested_at_rules() {
+            let css = ".e { @media (x) { .f { --no-value:; } } }".to_string();
+            let mut parser = StylesheetParser::default();
+            parser.parse_stylesheet(css);
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(parser.errors[0].offset, 23);
         }
 
         #[test]
-        #[should_panic]
         pub fn test_lint_broken_property() {
             let css =
                 "span :deep(*:not(a)) { ani ation: fancy-animation 1s infinite; }".to_string();
             let mut parser = StylesheetParser::default();
             parser.parse_stylesheet(css);
-            parser.stylesheet.compile("random_test_class");
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(parser.errors[0].offset, 23);
+        }
+
+        #[test]
+        pub fn test_lint_reports_all_errors() {
+            let css = "span {\n  ani ation: fancy;\n  --no-value:;\n}\n.a { b c: d; }".to_string();
+            let mut parser = StylesheetParser::default();
+            parser.parse_stylesheet(css);
+            let offsets = parser
+                .errors
+                .iter()
+                .map(|error| error.offset)
+                .collect::<Vec<_>>();
+            assert_eq!(offsets, vec![9, 29, 49]);
         }
 
         #[test]
@@ -328,6 +1911,7 @@ mod test {
                 .to_string();
             let mut parser = StylesheetParser::default();
             parser.parse_stylesheet(css);
+            assert!(parser.errors.is_empty());
             let compiled = parser.stylesheet.compile("random_test_class");
             assert_eq!(compiled, "span.random_test_class { animation : fancy-animation 1s infinite; --custom-🚀-prop: '🌵' }")
         }
@@ -0,0 +1,532 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::css;
+
+const FRAGMENT_EXTENSION: &str = "css";
+/// Locked while reading or writing the fragments, which rustc processes of different crates do
+/// concurrently
+const LOCK_FILE: &str = ".lock";
+/// Starts the header of every fragment, fragments written in another format are ignored
+const FORMAT: &str = "unstyled-fragment-3";
+const GLOBAL: &str = "global";
+const SCOPED: &str = "scoped";
+
+const BIN: &str = "bin";
+const LIB: &str = "lib";
+
+/// The scope classes this process wrote to each fragment directory, with their origins
+static WRITTEN: Mutex<BTreeMap<PathBuf, BTreeSet<(String, Origin)>>> = Mutex::new(BTreeMap::new());
+/// The fragments this process read or wrote by their path, as every `style!` merges all of them
+static CACHE: Mutex<BTreeMap<PathBuf, Cached>> = Mutex::new(BTreeMap::new());
+
+///
+/// The location of the `style!` invocation a fragment was written by. The merged stylesheet is
+/// ordered by it, so the output is the same for every build of the same sources.
+///
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Origin {
+    pub crate_name: String,
+    /// Whether the crate is a binary, which usually has the same name as the library of its package
+    pub bin: bool,
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    /// The absolute path of the source file, empty when unknown. The origin is dropped once the
+    /// file no longer exists.
+    pub source: PathBuf,
+}
+
+impl Origin {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.crate_name,
+            if self.bin { BIN } else { LIB },
+            self.file,
+            self.line,
+            self.column,
+            self.source.display()
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.split('\t');
+        let origin = Self {
+            crate_name: parts.next()?.to_string(),
+            bin: match parts.next()? {
+                BIN => true,
+                LIB => false,
+                _ => return None,
+            },
+            file: parts.next()?.to_string(),
+            line: parts.next()?.parse().ok()?,
+            column: parts.next()?.parse().ok()?,
+            source: PathBuf::from(parts.next()?),
+        };
+
+        parts.next().is_none().then_some(origin)
+    }
+
+    fn source_exists(&self) -> bool {
+        self.source.as_os_str().is_empty() || self.source.exists()
+    }
+
+    fn crate_id(&self) -> (&str, bool) {
+        (&self.crate_name, self.bin)
+    }
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.bin { "binary" } else { "crate" };
+
+        write!(
+            f,
+            "{}:{}:{} ({kind} {})",
+            self.file, self.line, self.column, self.crate_name
+        )
+    }
+}
+
+///
+/// The compiled css of a single scope class, or of a global style
+///
+pub(crate) struct Fragment {
+    /// Global styles come first in the merged stylesheet, so scoped styles can override them
+    pub global: bool,
+    /// The invocations using the style in order, the first of which places it in the merged
+    /// stylesheet
+    pub origins: Vec<Origin>,
+    /// Identifies the style the css was compiled from, two fragments of the same scope class with
+    /// different digests are a hash collision
+    pub digest: u64,
+    pub css: String,
+}
+
+impl Fragment {
+    ///
+    /// A fragment is stored as a tab separated header line, holding the format, its kind, digest
+    /// and the number of its origins, followed by a line per origin and the compiled css
+    ///
+    fn to_file(&self) -> String {
+        let kind = if self.global { GLOBAL } else { SCOPED };
+        let mut file = format!(
+            "{FORMAT}\t{kind}\t{:016x}\t{}\n",
+            self.digest,
+            self.origins.len()
+        );
+
+        for origin in &self.origins {
+            file.push_str(&origin.to_line());
+            file.push('\n');
+        }
+
+        file + &self.css
+    }
+
+    fn from_file(content: &str) -> Option<Self> {
+        let (header, mut rest) = content.split_once('\n').unwrap_or((content, ""));
+        let mut parts = header.split('\t');
+
+        if parts.next()? != FORMAT {
+            return None;
+        }
+
+        let global = match parts.next()? {
+            GLOBAL => true,
+            SCOPED => false,
+            _ => return None,
+        };
+        let digest = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let count = parts.next()?.parse::<usize>().ok()?;
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let mut origins = Vec::new();
+
+        for _ in 0..count {
+            let (line, next) = rest.split_once('\n')?;
+            origins.push(Origin::from_line(line)?);
+            rest = next;
+        }
+
+        Some(Self {
+            global,
+            origins,
+            digest,
+            css: rest.to_string(),
+        })
+    }
+
+    fn read(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+
+        Self::from_file(&content).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a style fragment", path.display()),
+            )
+        })
+    }
+}
+
+///
+/// The length, modification time and (on unix) inode of a file. Fragments are replaced by a
+/// rename, so a fragment written by another process gets a new inode even within the same tick
+/// of a coarse modification time.
+///
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: u64,
+}
+
+impl Stamp {
+    fn read(path: &Path) -> io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode,
+        })
+    }
+}
+
+///
+/// A fragment as this process last read or wrote it
+///
+struct Cached {
+    /// The file the fragment was read from, it is read again once the file changes
+    stamp: Option<Stamp>,
+    fragment: Fragment,
+    /// The leading rules of the css and the rest of it, split when it is merged for the first time
+    split: Option<(Vec<String>, String)>,
+}
+
+impl Cached {
+    fn new(fragment: Fragment) -> Self {
+        Self {
+            stamp: None,
+            fragment,
+            split: None,
+        }
+    }
+
+    ///
+    /// Reads the fragment, unless the file didn't change since this process last read or wrote it
+    ///
+    fn read<'a>(cache: &'a mut BTreeMap<PathBuf, Cached>, path: &Path) -> io::Result<&'a mut Self> {
+        let stamp = Stamp::read(path)?;
+        let fresh = cache
+            .get(path)
+            .is_some_and(|cached| cached.stamp == Some(stamp) && stamp.modified.is_some());
+
+        if !fresh {
+            match Fragment::read(path) {
+                Ok(fragment) => {
+                    let mut cached = Cached::new(fragment);
+                    cached.stamp = Some(stamp);
+                    cache.insert(path.to_path_buf(), cached);
+                }
+                Err(error) => {
+                    cache.remove(path);
+                    return Err(error);
+                }
+            }
+        }
+
+        cache
+            .get_mut(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    ///
+    /// Writes the fragment, or removes it when no invocation uses its style anymore
+    ///
+    fn write(&mut self, path: &Path) -> io::Result<()> {
+        if self.fragment.origins.is_empty() {
+            self.stamp = None;
+
+            return std::fs::remove_file(path);
+        }
+
+        write_atomic(path, &self.fragment.to_file())?;
+        self.stamp = Stamp::read(path).ok();
+
+        Ok(())
+    }
+
+    fn split(&mut self) -> &(Vec<String>, String) {
+        self.split
+            .get_or_insert_with(|| css::split_leading_rules(&self.fragment.css))
+    }
+}
+
+///
+/// Writes the file through a temporary file, so concurrently running macros (e.g. of different
+/// crates) never see a half written file. Missing parent directories are created.
+///
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
+
+///
+/// Locks the fragment directory until the returned file is dropped
+///
+fn lock(config: &Config) -> io::Result<File> {
+    std::fs::create_dir_all(&config.fragment_dir)?;
+
+    let lock = File::create(config.fragment_dir.join(LOCK_FILE))?;
+    lock.lock()?;
+
+    Ok(lock)
+}
+
+///
+/// Reads all fragments into the cache and returns their paths. Only the fragments written by
+/// another process since they were last read are parsed again. Fragments written by another
+/// version of unstyled are removed, their styles are written again once their macros are expanded
+/// by this one.
+///
+fn read_fragments(
+    config: &Config,
+    cache: &mut BTreeMap<PathBuf, Cached>,
+) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+
+    for entry in std::fs::read_dir(&config.fragment_dir)? {
+        let path = entry?.path();
+
+        if path.extension().and_then(|extension| extension.to_str()) != Some(FRAGMENT_EXTENSION) {
+            continue;
+        }
+
+        match Cached::read(cache, &path) {
+            Ok(_) => paths.push(path),
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                let _ = std::fs::remove_file(&path);
+            }
+            // Removed by another process in the meantime
+            Err(_) => {}
+        }
+    }
+
+    Ok(paths)
+}
+
+///
+/// Persists the compiled css of a single scope class.
+///
+/// Every `style!` invocation persists its compiled css as a fragment, one file per scope class.
+/// This way the merged stylesheet also contains the styles of macros, which were not re-expanded
+/// by an incremental build.
+///
+/// A fragment keeps the origins of all invocations using its style, so its place in the merged
+/// stylesheet is independent of the order the macros are expanded in. A different style with the
+/// same scope class is rejected as a hash collision.
+///
+pub(crate) fn write_fragment(
+    config: &Config,
+    scope_class: &str,
+    mut fragment: Fragment,
+) -> Result<(), String> {
+    let error = |error: io::Error| format!("Could not write the style of {scope_class}: {error}");
+    let path = config
+        .fragment_dir
+        .join(scope_class)
+        .with_extension(FRAGMENT_EXTENSION);
+    let _lock = lock(config).map_err(error)?;
+    let mut written = WRITTEN.lock().unwrap_or_else(|error| error.into_inner());
+    let written = written.entry(config.fragment_dir.clone()).or_default();
+    let mut cache = CACHE.lock().unwrap_or_else(|error| error.into_inner());
+    let mut split = None;
+
+    for origin in &fragment.origins {
+        written.insert((scope_class.to_string(), origin.clone()));
+    }
+
+    match Cached::read(&mut cache, &path) {
+        Ok(other) if other.fragment.digest != fragment.digest => {
+            return Err(format!(
+                "The scope class {scope_class} of the style at {} collides with the different \
+                style at {}. Set a `salt` or increase the `hash-length` in \
+                [package.metadata.unstyled] to resolve it. When the other style no longer exists, \
+                `cargo clean` removes it.",
+                fragment.origins[0], other.fragment.origins[0]
+            ));
+        }
+        Ok(other) => {
+            fragment.origins.extend_from_slice(&other.fragment.origins);
+            fragment.origins.sort();
+            fragment.origins.dedup();
+
+            if other.fragment.css == fragment.css {
+                split = other.split.take();
+            }
+        }
+        // Written by another version of unstyled, or not at all
+        Err(_) => {}
+    }
+
+    let mut cached = Cached::new(fragment);
+    cached.split = split;
+    cached.write(&path).map_err(error)?;
+    cache.insert(path, cached);
+
+    Ok(())
+}
+
+///
+/// Rebuilds the merged stylesheet from all fragments written so far, the global ones first, each
+/// ordered by their origin.
+///
+/// Origins in source files, which no longer exist, are dropped. So are the origins of the crates
+/// this process compiles, which it didn't write, as rustc expands every macro of a crate it
+/// compiles. The styles left behind by edited, moved or removed macros are dropped this way, and
+/// the stylesheet only depends on the current sources.
+///
+/// As every `style!` merges the stylesheet, the fragments are kept in memory. Only the ones
+/// another process wrote since are read again.
+///
+/// The merge holds a lock, so a process which read the fragments earlier can't overwrite the
+/// stylesheet merged by a later one, which might contain more fragments.
+///
+/// A namespace prefix declared by multiple styles is written once, it fails when they declare
+/// it with different urls, as only the last declaration would apply.
+///
+pub(crate) fn write_stylesheet(config: &Config) -> io::Result<()> {
+    let _lock = lock(config)?;
+    let written = WRITTEN
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .get(&config.fragment_dir)
+        .cloned()
+        .unwrap_or_default();
+    let compiled = written
+        .iter()
+        .map(|(_, origin)| origin.crate_id())
+        .collect::<BTreeSet<_>>();
+    let mut cache = CACHE.lock().unwrap_or_else(|error| error.into_inner());
+    let mut paths = Vec::new();
+
+    for path in read_fragments(config, &mut cache)? {
+        let Some(cached) = cache.get_mut(&path) else {
+            continue;
+        };
+        let scope_class = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let count = cached.fragment.origins.len();
+
+        cached.fragment.origins.retain(|origin| {
+            origin.source_exists()
+                && (!compiled.contains(&origin.crate_id())
+                    || written.contains(&(scope_class.clone(), origin.clone())))
+        });
+
+        if cached.fragment.origins.len() != count {
+            cached.write(&path)?;
+        }
+
+        if cached.fragment.origins.is_empty() {
+            cache.remove(&path);
+        } else {
+            cached.split();
+            paths.push(path);
+        }
+    }
+
+    let mut fragments = paths
+        .iter()
+        .filter_map(|path| cache.get(path))
+        .collect::<Vec<_>>();
+
+    // Sorting by the css as well keeps the order stable for fragments with the same origin
+    fragments.sort_by(|a, b| {
+        let (a, b) = (&a.fragment, &b.fragment);
+
+        b.global
+            .cmp(&a.global)
+            .then_with(|| a.origins[0].cmp(&b.origins[0]))
+            .then_with(|| a.css.cmp(&b.css))
+    });
+
+    let mut leading_rules = Vec::new();
+    // The prefix, url and origin of every namespace declared so far
+    let mut namespaces: Vec<(String, String, &Origin)> = Vec::new();
+    let mut styles = Vec::new();
+
+    for cached in fragments {
+        let fragment = &cached.fragment;
+        let Some((rules, css)) = &cached.split else {
+            continue;
+        };
+
+        for rule in rules {
+            if let Some((prefix, url)) = css::parse_namespace_rule(rule) {
+                match namespaces.iter().find(|(other, ..)| *other == prefix) {
+                    Some((_, other_url, _)) if *other_url == url => continue,
+                    Some((_, other_url, other_origin)) => {
+                        let name = if prefix.is_empty() {
+                            "The default namespace".to_string()
+                        } else {
+                            format!("The namespace prefix \"{prefix}\"")
+                        };
+
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{name} is declared as \"{url}\" by the style at {}, but as \
+                                \"{other_url}\" by the style at {other_origin}",
+                                fragment.origins[0]
+                            ),
+                        ));
+                    }
+                    None => namespaces.push((prefix, url, &fragment.origins[0])),
+                }
+            }
+
+            if !leading_rules.contains(rule) {
+                leading_rules.push(rule.clone());
+            }
+        }
+
+        if !css.trim().is_empty() {
+            styles.push(css.as_str());
+        }
+    }
+
+    css::sort_leading_rules(&mut leading_rules);
+
+    let stylesheet = leading_rules
+        .iter()
+        .map(String::as_str)
+        .chain(styles)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    write_atomic(&config.output, &stylesheet)
+}